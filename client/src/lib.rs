@@ -0,0 +1,502 @@
+//! Async client for game server plugins talking to the tc-discord bridge
+//!
+//! [`Client::connect`] authenticates with the bridge, then keeps the connection alive in the
+//! background: it reconnects with backoff whenever the connection drops, waits as long as the
+//! bridge asks when it is shutting down, and registers the server again once reconnected.
+//! Commands sent from discord arrive on [`Commands`]. `wss://` bridges are verified against the
+//! usual web roots unless [`ClientConfig::ca`] says otherwise
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use tc_discord_client::{Client, ClientConfig};
+//!
+//! # async fn run() -> Result<(), tc_discord_client::ClientError> {
+//! let (client, mut commands) =
+//!     Client::connect(ClientConfig::new("ws://localhost:8080", "plugin secret")).await?;
+//! client.set_name("lobby")?;
+//! client.set_control_channel("123456789")?;
+//!
+//! while let Some(command) = commands.next().await {
+//!     client.respond(command.request_id, command.exec.run.clone(), Default::default(), Default::default())?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use futures::{SinkExt, Stream, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use log::{debug, info, warn};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tokio_rustls::rustls::{self, internal::pemfile, PrivateKey};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+use uuid::Uuid;
+
+pub use tc_discord_protocol as protocol;
+use tc_discord_protocol::{
+    CommandResponsePacket, ErrorType, Exec, IncomingPacket, OutgoingPacket,
+    CAPABILITY_COMMAND_RESPONSE, PROTOCOL_VERSION,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("Websocket error, {0}")]
+    Websocket(Box<tungstenite::Error>),
+    #[error("Bridge refused the connection, {0:?}: {1}")]
+    Refused(ErrorType, String),
+    #[error("Connection closed during the handshake")]
+    Closed,
+    #[error("Client has stopped")]
+    Stopped,
+    #[error("Could not read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Invalid TLS configuration, {0}")]
+    Tls(String),
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(err: tungstenite::Error) -> Self {
+        ClientError::Websocket(Box::new(err))
+    }
+}
+
+/// Where to connect and how to behave once connected
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Websocket URL of the bridge, `ws://` or `wss://`
+    pub url: String,
+    /// Shared secret the bridge challenges plugins with
+    pub secret: String,
+    /// Capabilities announced to the bridge in the hello
+    pub capabilities: Vec<String>,
+    /// Delay before the first reconnect attempt, doubled after every failed attempt
+    pub reconnect_delay: Duration,
+    /// Longest delay between reconnect attempts
+    pub max_reconnect_delay: Duration,
+    /// PEM certificates a `wss://` bridge's certificate must be signed by, instead of the usual
+    /// web roots
+    pub ca: Option<PathBuf>,
+    /// PEM certificate chain, leaf first, for bridges that require plugins to identify themselves
+    pub cert: Option<PathBuf>,
+    /// PEM PKCS#8 or RSA private key of `cert`
+    pub key: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    pub fn new(url: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            secret: secret.into(),
+            capabilities: vec![CAPABILITY_COMMAND_RESPONSE.to_string()],
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+            ca: None,
+            cert: None,
+            key: None,
+        }
+    }
+
+    /// Rustls settings when the defaults don't do. Files are read again on every connection
+    /// attempt, so renewed certificates are picked up when reconnecting
+    fn connector(&self) -> Result<Option<Connector>, ClientError> {
+        if self.ca.is_none() && self.cert.is_none() && self.key.is_none() {
+            return Ok(None);
+        }
+
+        let mut tls = rustls::ClientConfig::new();
+        match &self.ca {
+            Some(ca) => {
+                for cert in read_pem(ca, pemfile::certs)? {
+                    tls.root_store
+                        .add(&cert)
+                        .map_err(|err| ClientError::Tls(format!("{}: {}", ca.display(), err)))?;
+                }
+            }
+            None => tls
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let certs = read_pem(cert, pemfile::certs)?;
+                if certs.is_empty() {
+                    return Err(ClientError::Tls(format!(
+                        "no certificates in {}",
+                        cert.display()
+                    )));
+                }
+                tls.set_single_client_cert(certs, read_key(key)?)
+                    .map_err(|err| ClientError::Tls(err.to_string()))?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ClientError::Tls(
+                    "cert and key must be set together".to_string(),
+                ))
+            }
+        }
+        Ok(Some(Connector::Rustls(Arc::new(tls))))
+    }
+}
+
+/// A command sent to this server from discord
+#[derive(Clone, Debug)]
+pub struct ServerRun {
+    /// Pass back to [`Client::respond`]
+    pub request_id: Uuid,
+    pub exec: Exec,
+}
+
+/// Handle to a connection kept alive in the background, dropping it disconnects
+pub struct Client {
+    requests: UnboundedSender<IncomingPacket>,
+}
+
+impl Client {
+    /// Connect and authenticate, failing if the first attempt does. Later disconnects are
+    /// retried in the background
+    pub async fn connect(config: ClientConfig) -> Result<(Client, Commands), ClientError> {
+        let socket = handshake(&config).await?;
+        info!("Connected to {}", config.url);
+
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(config, socket, requests_rx, commands));
+
+        Ok((Client { requests }, Commands(commands_rx)))
+    }
+
+    /// Set the name commands address this server by
+    pub fn set_name(&self, name: impl Into<String>) -> Result<(), ClientError> {
+        self.send(IncomingPacket::SetName(name.into()))
+    }
+
+    /// Set the discord channel this server is controlled from
+    pub fn set_control_channel(
+        &self,
+        ctrl_channel_id: impl Into<String>,
+    ) -> Result<(), ClientError> {
+        self.send(IncomingPacket::SetControlChannel(ctrl_channel_id.into()))
+    }
+
+    /// Set the key/value tags, such as `region=eu`, that selectors can target this server by
+    pub fn set_tags(&self, tags: BTreeMap<String, String>) -> Result<(), ClientError> {
+        self.send(IncomingPacket::SetTags(tags))
+    }
+
+    /// Answer a [`ServerRun`], the bridge posts the response where the command came from
+    pub fn respond(
+        &self,
+        request_id: Uuid,
+        run: Vec<String>,
+        query: HashMap<String, String>,
+        set: HashMap<String, bool>,
+    ) -> Result<(), ClientError> {
+        self.send(IncomingPacket::CommandResponse(CommandResponsePacket {
+            request_id,
+            run,
+            query,
+            set,
+        }))
+    }
+
+    fn send(&self, packet: IncomingPacket) -> Result<(), ClientError> {
+        self.requests.send(packet).map_err(|_| ClientError::Stopped)
+    }
+}
+
+/// Commands sent to this server, ends once the [`Client`] is dropped
+pub struct Commands(UnboundedReceiver<ServerRun>);
+
+impl Stream for Commands {
+    type Item = ServerRun;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerRun>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Why a connection stopped being served
+enum Ended {
+    /// The [`Client`] was dropped
+    Stopped,
+    /// The bridge is shutting down and asked us to come back after a while
+    GoingAway(Duration),
+    /// The connection broke or the bridge closed it
+    Dropped,
+}
+
+/// What registers the server with the bridge, sent again after reconnecting
+#[derive(Default)]
+struct Registration {
+    tags: Option<BTreeMap<String, String>>,
+    name: Option<String>,
+    ctrl_channel_id: Option<String>,
+}
+
+impl Registration {
+    /// Remember `packet` if it is part of the registration
+    fn remember(&mut self, packet: &IncomingPacket) {
+        match packet {
+            IncomingPacket::SetName(name) => self.name = Some(name.clone()),
+            IncomingPacket::SetControlChannel(ctrl_channel_id) => {
+                self.ctrl_channel_id = Some(ctrl_channel_id.clone())
+            }
+            IncomingPacket::SetTags(tags) => self.tags = Some(tags.clone()),
+            _ => {}
+        }
+    }
+
+    fn packets(&self) -> Vec<IncomingPacket> {
+        let mut packets = vec![];
+        // Tags go first, so the server is selectable by them as soon as it is online
+        if let Some(tags) = &self.tags {
+            packets.push(IncomingPacket::SetTags(tags.clone()));
+        }
+        if let Some(name) = &self.name {
+            packets.push(IncomingPacket::SetName(name.clone()));
+        }
+        if let Some(ctrl_channel_id) = &self.ctrl_channel_id {
+            packets.push(IncomingPacket::SetControlChannel(ctrl_channel_id.clone()));
+        }
+        packets
+    }
+}
+
+/// Serve `socket`, then keep reconnecting until the [`Client`] is dropped
+async fn run(
+    config: ClientConfig,
+    socket: Socket,
+    mut requests: UnboundedReceiver<IncomingPacket>,
+    commands: UnboundedSender<ServerRun>,
+) {
+    let mut registration = Registration::default();
+    let mut socket = Some(socket);
+    let mut delay = config.reconnect_delay;
+
+    loop {
+        let mut current = match socket.take() {
+            Some(current) => current,
+            None => match handshake(&config).await {
+                Ok(current) => {
+                    info!("Reconnected to {}", config.url);
+                    delay = config.reconnect_delay;
+                    current
+                }
+                Err(err) => {
+                    warn!("Reconnecting to {} failed, {}", config.url, err);
+                    if !wait(delay, &mut requests, &mut registration).await {
+                        return;
+                    }
+                    delay = (delay * 2).min(config.max_reconnect_delay);
+                    continue;
+                }
+            },
+        };
+
+        let ended = match register(&mut current, &registration).await {
+            Ok(()) => serve(&mut current, &mut requests, &mut registration, &commands).await,
+            Err(_) => Ended::Dropped,
+        };
+        let reconnect_after = match ended {
+            Ended::Stopped => {
+                current.close(None).await.unwrap_or(());
+                return;
+            }
+            Ended::GoingAway(reconnect_after) => {
+                info!(
+                    "Bridge is going away, reconnecting in {}s",
+                    reconnect_after.as_secs()
+                );
+                reconnect_after
+            }
+            Ended::Dropped => {
+                warn!("Lost connection to {}", config.url);
+                delay
+            }
+        };
+        if !wait(reconnect_after, &mut requests, &mut registration).await {
+            return;
+        }
+    }
+}
+
+/// Sleep while disconnected, remembering registration changes. Anything else sent meanwhile is
+/// dropped, the bridge forgets pending commands when a server disconnects anyway. False once the
+/// [`Client`] is dropped
+async fn wait(
+    duration: Duration,
+    requests: &mut UnboundedReceiver<IncomingPacket>,
+    registration: &mut Registration,
+) -> bool {
+    let sleep = tokio::time::sleep(duration);
+    tokio::pin!(sleep);
+    loop {
+        tokio::select! {
+            _ = &mut sleep => return true,
+            request = requests.recv() => match request {
+                Some(packet) => registration.remember(&packet),
+                None => return false,
+            },
+        }
+    }
+}
+
+async fn register(socket: &mut Socket, registration: &Registration) -> Result<(), ClientError> {
+    for packet in registration.packets() {
+        send(socket, &packet).await?;
+    }
+    Ok(())
+}
+
+async fn serve(
+    socket: &mut Socket,
+    requests: &mut UnboundedReceiver<IncomingPacket>,
+    registration: &mut Registration,
+    commands: &UnboundedSender<ServerRun>,
+) -> Ended {
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let packet = match request {
+                    Some(packet) => packet,
+                    None => return Ended::Stopped,
+                };
+                registration.remember(&packet);
+                if send(socket, &packet).await.is_err() {
+                    return Ended::Dropped;
+                }
+            }
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => match OutgoingPacket::parse(&text) {
+                    Ok(OutgoingPacket::ServerRun(request_id, exec)) => {
+                        // Nobody is listening for commands, which is the caller's choice
+                        commands.send(ServerRun { request_id, exec }).unwrap_or(());
+                    }
+                    Ok(OutgoingPacket::GoingAway(reconnect_after)) => {
+                        return Ended::GoingAway(Duration::from_secs(reconnect_after))
+                    }
+                    Ok(OutgoingPacket::Error(error, message)) => {
+                        warn!("Bridge sent an error, {:?}: {}", error, message)
+                    }
+                    Ok(packet) => debug!("Ignoring {} packet", packet.name()),
+                    Err(err) => warn!("Error parsing packet from the bridge, {}", err),
+                },
+                // Pings are answered by tungstenite itself
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ended::Dropped,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// Connect, say hello and answer the auth challenge
+async fn handshake(config: &ClientConfig) -> Result<Socket, ClientError> {
+    let connector = config.connector()?;
+    let request = config.url.as_str().into_client_request()?;
+    let uri = request.uri();
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("wss")) => 443,
+        (None, _) => 80,
+    };
+    let addr = format!("{}:{}", uri.host().unwrap_or_default(), port);
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(tungstenite::Error::Io)?;
+    let (mut socket, _) =
+        tokio_tungstenite::client_async_tls_with_config(request, stream, None, connector).await?;
+
+    let challenge = loop {
+        match next_packet(&mut socket).await? {
+            OutgoingPacket::AuthChallenge(challenge) => break challenge,
+            OutgoingPacket::Error(error, message) => {
+                return Err(ClientError::Refused(error, message))
+            }
+            _ => {}
+        }
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(challenge.as_bytes());
+    let response = hex::encode(mac.finalize().into_bytes());
+
+    send(
+        &mut socket,
+        &IncomingPacket::Hello(PROTOCOL_VERSION, config.capabilities.clone()),
+    )
+    .await?;
+    send(&mut socket, &IncomingPacket::Auth(response)).await?;
+
+    loop {
+        match next_packet(&mut socket).await? {
+            OutgoingPacket::Authenticated => return Ok(socket),
+            OutgoingPacket::Error(error, message) => {
+                return Err(ClientError::Refused(error, message))
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn next_packet(socket: &mut Socket) -> Result<OutgoingPacket, ClientError> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => match OutgoingPacket::parse(&text) {
+                Ok(packet) => return Ok(packet),
+                Err(err) => warn!("Error parsing packet from the bridge, {}", err),
+            },
+            Some(Ok(Message::Close(_))) | None => return Err(ClientError::Closed),
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
+
+async fn send(socket: &mut Socket, packet: &IncomingPacket) -> Result<(), ClientError> {
+    let text = serde_json::to_string(packet).expect("Only valid packets are sent");
+    socket.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: fn(&mut dyn io::BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>, ClientError> {
+    let file = File::open(path).map_err(|source| ClientError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(&mut BufReader::new(file)).map_err(|_| ClientError::Read {
+        path: path.to_path_buf(),
+        source: io::Error::new(io::ErrorKind::InvalidData, "invalid PEM"),
+    })
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, ClientError> {
+    let mut keys = read_pem(path, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(path, pemfile::rsa_private_keys)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| ClientError::Tls(format!("no private key in {}", path.display())))
+}
@@ -0,0 +1,367 @@
+//! Packets plugins send to the bridge
+use anyhow::anyhow;
+use serde::{ser::Error, Deserialize, Serialize, Serializer};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+macro_rules! parse_packet {
+    ($a:ident) => {
+        match serde_json::from_str(&$a) {
+            Err(e) => return IncomingPacket::Invalid(anyhow!(e)),
+            Ok(a) => a,
+        }
+    };
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PacketBase {
+    id: i32,
+}
+
+/// Packet for setting the name of the server
+/// # Packet Structure
+/// ```text
+/// id: 0
+/// name String
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SetNamePacket {
+    name: String,
+}
+
+/// Packet for setting the discord channel the server is controlled from
+/// # Packet Structure
+/// ```text
+/// id: 1
+/// ctrlChannelId: String
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SetServerPacket {
+    ctrl_channel_id: String,
+}
+
+/// Packet for reporting the outcome of a `ServerRun` packet back to discord
+/// # Packet Structure
+/// ```text
+/// id: 2
+/// requestId: String
+/// run: String[]
+/// query: Map<String, String>
+/// set: Map<String, bool>
+/// ```
+/// `run` holds the output of each command, in the order they were sent
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResponsePacket {
+    pub request_id: Uuid,
+    #[serde(default = "Default::default")]
+    pub run: Vec<String>,
+    #[serde(default = "Default::default")]
+    pub query: HashMap<String, String>,
+    #[serde(default = "Default::default")]
+    pub set: HashMap<String, bool>,
+}
+
+/// Packet for answering the challenge sent when the plugin connects
+/// # Packet Structure
+/// ```text
+/// id: 3
+/// response: String
+/// ```
+/// `response` is the hex encoded HMAC-SHA256 of the challenge, keyed with the shared secret
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AuthPacket {
+    response: String,
+}
+
+/// Packet for agreeing on a protocol version, should be the first packet a plugin sends
+/// # Packet Structure
+/// ```text
+/// id: 4
+/// version: u32
+/// capabilities: String[]
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HelloPacket {
+    version: u32,
+    #[serde(default = "Default::default")]
+    capabilities: Vec<String>,
+}
+
+/// Packet for relaying something a player said in game to the bridge channel
+/// # Packet Structure
+/// ```text
+/// id: 5
+/// player: String
+/// text: String
+/// avatar: String?
+/// ```
+/// `avatar` is a URL to the player's avatar, used as the avatar of the relayed message
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessagePacket {
+    pub player: String,
+    pub text: String,
+    #[serde(default = "Default::default")]
+    pub avatar: Option<String>,
+}
+
+/// Packet for setting the discord channel in game chat is bridged with
+/// # Packet Structure
+/// ```text
+/// id: 6
+/// bridgeChannelId: String
+/// ```
+/// Must be a different channel than the control channel
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SetBridgeChannelPacket {
+    bridge_channel_id: String,
+}
+
+/// Packet for reporting something that happened on the server
+/// # Packet Structure
+/// ```text
+/// id: 7
+/// kind: String
+/// ```
+/// Followed by the fields of the kind
+/// ```text
+/// playerJoined: player String
+/// playerLeft: player String
+/// playerDied: player String, message String?
+/// playerKicked: player String, reason String?
+/// starting
+/// stopping
+/// crashed: reason String?
+/// ```
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ServerEventKind {
+    PlayerJoined {
+        player: String,
+    },
+    PlayerLeft {
+        player: String,
+    },
+    PlayerDied {
+        player: String,
+        #[serde(default = "Default::default")]
+        message: Option<String>,
+    },
+    PlayerKicked {
+        player: String,
+        #[serde(default = "Default::default")]
+        reason: Option<String>,
+    },
+    Starting,
+    Stopping,
+    Crashed {
+        #[serde(default = "Default::default")]
+        reason: Option<String>,
+    },
+}
+
+/// Packet for identifying the server across reconnects, should be sent before `SetName`
+/// # Packet Structure
+/// ```text
+/// id: 8
+/// serverId: String
+/// ```
+/// A server that reconnects with the same `serverId` gets its old uuid back, and replaces its old
+/// connection if that is still open
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SetServerIdPacket {
+    server_id: String,
+}
+
+/// Packet for acknowledging packets sent on a resumable session
+/// # Packet Structure
+/// ```text
+/// id: 9
+/// seq: u64
+/// ```
+/// Acknowledges every packet up to and including `seq`, those are not replayed on resume
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AckPacket {
+    seq: u64,
+}
+
+/// Packet for picking up a session after reconnecting, sent instead of `SetName` and
+/// `SetControlChannel`
+/// # Packet Structure
+/// ```text
+/// id: 10
+/// resumeToken: String
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ResumePacket {
+    resume_token: String,
+}
+
+/// Packet for announcing key/value tags, such as `region=eu`, that selectors can target
+/// # Packet Structure
+/// ```text
+/// id: 11
+/// tags: Map<String, String>
+/// ```
+/// Replaces any tags sent before
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SetTagsPacket {
+    tags: BTreeMap<String, String>,
+}
+
+/// Struct to represent any incoming packet
+#[derive(Debug)]
+pub enum IncomingPacket {
+    SetName(String),
+    SetControlChannel(String),
+    CommandResponse(CommandResponsePacket),
+    Auth(String),
+    Hello(u32, Vec<String>),
+    ChatMessage(ChatMessagePacket),
+    SetBridgeChannel(String),
+    ServerEvent(ServerEventKind),
+    SetServerId(String),
+    Ack(u64),
+    Resume(String),
+    SetTags(BTreeMap<String, String>),
+    InvalidID,
+    Invalid(anyhow::Error),
+}
+
+impl IncomingPacket {
+    /// Name of the packet, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            IncomingPacket::SetName(_) => "SetName",
+            IncomingPacket::SetControlChannel(_) => "SetControlChannel",
+            IncomingPacket::CommandResponse(_) => "CommandResponse",
+            IncomingPacket::Auth(_) => "Auth",
+            IncomingPacket::Hello(..) => "Hello",
+            IncomingPacket::ChatMessage(_) => "ChatMessage",
+            IncomingPacket::SetBridgeChannel(_) => "SetBridgeChannel",
+            IncomingPacket::ServerEvent(_) => "ServerEvent",
+            IncomingPacket::SetServerId(_) => "SetServerId",
+            IncomingPacket::Ack(_) => "Ack",
+            IncomingPacket::Resume(_) => "Resume",
+            IncomingPacket::SetTags(_) => "SetTags",
+            IncomingPacket::InvalidID => "InvalidID",
+            IncomingPacket::Invalid(_) => "Invalid",
+        }
+    }
+}
+
+impl From<String> for IncomingPacket {
+    fn from(source: String) -> Self {
+        let PacketBase { id } = match serde_json::from_str::<PacketBase>(&source) {
+            Ok(x) => x,
+            Err(err) => {
+                return IncomingPacket::Invalid(anyhow!(err));
+            }
+        };
+
+        match id {
+            0 => {
+                let SetNamePacket { name } = parse_packet!(source);
+                IncomingPacket::SetName(name)
+            }
+            1 => {
+                let SetServerPacket { ctrl_channel_id } = parse_packet!(source);
+                IncomingPacket::SetControlChannel(ctrl_channel_id)
+            }
+            2 => IncomingPacket::CommandResponse(parse_packet!(source)),
+            3 => {
+                let AuthPacket { response } = parse_packet!(source);
+                IncomingPacket::Auth(response)
+            }
+            4 => {
+                let HelloPacket {
+                    version,
+                    capabilities,
+                } = parse_packet!(source);
+                IncomingPacket::Hello(version, capabilities)
+            }
+            5 => IncomingPacket::ChatMessage(parse_packet!(source)),
+            6 => {
+                let SetBridgeChannelPacket { bridge_channel_id } = parse_packet!(source);
+                IncomingPacket::SetBridgeChannel(bridge_channel_id)
+            }
+            7 => IncomingPacket::ServerEvent(parse_packet!(source)),
+            8 => {
+                let SetServerIdPacket { server_id } = parse_packet!(source);
+                IncomingPacket::SetServerId(server_id)
+            }
+            9 => {
+                let AckPacket { seq } = parse_packet!(source);
+                IncomingPacket::Ack(seq)
+            }
+            10 => {
+                let ResumePacket { resume_token } = parse_packet!(source);
+                IncomingPacket::Resume(resume_token)
+            }
+            11 => {
+                let SetTagsPacket { tags } = parse_packet!(source);
+                IncomingPacket::SetTags(tags)
+            }
+            _ => IncomingPacket::InvalidID,
+        }
+    }
+}
+
+/// How plugins send packets, the bridge only ever parses them
+impl Serialize for IncomingPacket {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let packet = match self {
+            IncomingPacket::SetName(name) => json!({ "id": 0, "name": name }),
+            IncomingPacket::SetControlChannel(ctrl_channel_id) => {
+                json!({ "id": 1, "ctrlChannelId": ctrl_channel_id })
+            }
+            IncomingPacket::CommandResponse(response) => {
+                let mut packet = serde_json::to_value(response).map_err(S::Error::custom)?;
+                packet["id"] = 2.into();
+                packet
+            }
+            IncomingPacket::Auth(response) => json!({ "id": 3, "response": response }),
+            IncomingPacket::Hello(version, capabilities) => {
+                json!({ "id": 4, "version": version, "capabilities": capabilities })
+            }
+            IncomingPacket::ChatMessage(message) => {
+                let mut packet = serde_json::to_value(message).map_err(S::Error::custom)?;
+                packet["id"] = 5.into();
+                packet
+            }
+            IncomingPacket::SetBridgeChannel(bridge_channel_id) => {
+                json!({ "id": 6, "bridgeChannelId": bridge_channel_id })
+            }
+            IncomingPacket::ServerEvent(kind) => {
+                let mut packet = serde_json::to_value(kind).map_err(S::Error::custom)?;
+                packet["id"] = 7.into();
+                packet
+            }
+            IncomingPacket::SetServerId(server_id) => json!({ "id": 8, "serverId": server_id }),
+            IncomingPacket::Ack(seq) => json!({ "id": 9, "seq": seq }),
+            IncomingPacket::Resume(resume_token) => {
+                json!({ "id": 10, "resumeToken": resume_token })
+            }
+            IncomingPacket::SetTags(tags) => json!({ "id": 11, "tags": tags }),
+            IncomingPacket::InvalidID | IncomingPacket::Invalid(_) => {
+                return Err(S::Error::custom("Invalid packets can't be sent"))
+            }
+        };
+        packet.serialize(serializer)
+    }
+}
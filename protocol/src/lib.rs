@@ -0,0 +1,35 @@
+//! Packets exchanged between the bridge and game server plugins over websockets
+//!
+//! Every packet is a JSON object with a numeric `id`. Incoming packets are sent by plugins,
+//! outgoing packets by the bridge
+mod incoming;
+mod outgoing;
+pub mod schema;
+
+pub use incoming::{ChatMessagePacket, CommandResponsePacket, IncomingPacket, ServerEventKind};
+pub use outgoing::*;
+
+/// Newest protocol version the bridge speaks, plugins that don't say hello are assumed to speak
+/// [`MIN_PROTOCOL_VERSION`]
+///
+/// # Versions
+/// 1. Original packet set, `ServerRun` has no request id
+/// 2. `ServerRun` carries a `requestId`, which `CommandResponse` packets refer back to
+/// 3. `GoingAway` is sent before the bridge shuts down, older plugins only see the socket close
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest protocol version the bridge still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Plugin replies to `ServerRun` packets with `CommandResponse` packets
+pub const CAPABILITY_COMMAND_RESPONSE: &str = "commandResponse";
+/// Plugin relays `ChatMessage` packets into game chat
+pub const CAPABILITY_CHAT: &str = "chat";
+/// Plugin acknowledges packets and resumes its session after reconnecting. Every packet sent once
+/// the server is registered carries a `seq`, which `Ack` packets refer back to
+pub const CAPABILITY_RESUME: &str = "resume";
+/// Every optional feature the bridge knows about
+pub const CAPABILITIES: &[&str] = &[
+    CAPABILITY_COMMAND_RESPONSE,
+    CAPABILITY_CHAT,
+    CAPABILITY_RESUME,
+];
@@ -0,0 +1,269 @@
+//! Packets the bridge sends to plugins
+use serde::ser::SerializeStruct;
+use serde::{de::Error, Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ErrorType {
+    PacketInvalidID,
+    PacketDeserializationError,
+    UnknownRequestID,
+    Unauthorized,
+    IncompatibleVersion,
+    InvalidBridgeChannel,
+    NoBridgeChannel,
+    NameConflict,
+    ResumeFailed,
+    Kicked,
+}
+
+impl ErrorType {
+    /// Every error type, in the order they were added
+    pub const ALL: &'static [ErrorType] = &[
+        ErrorType::PacketInvalidID,
+        ErrorType::PacketDeserializationError,
+        ErrorType::UnknownRequestID,
+        ErrorType::Unauthorized,
+        ErrorType::IncompatibleVersion,
+        ErrorType::InvalidBridgeChannel,
+        ErrorType::NoBridgeChannel,
+        ErrorType::NameConflict,
+        ErrorType::ResumeFailed,
+        ErrorType::Kicked,
+    ];
+}
+
+/// What a `ServerRun` packet asks the server to do
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Exec {
+    /// Commands to run, in order
+    #[serde(default = "Default::default")]
+    pub run: Vec<String>,
+    /// Values to read
+    #[serde(default = "Default::default")]
+    pub query: Vec<String>,
+    /// Values to change
+    #[serde(default = "Default::default")]
+    pub set: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum OutgoingPacket {
+    Error(ErrorType, String),
+    ServerRun(Uuid, Exec),
+    AuthChallenge(String),
+    Authenticated,
+    Hello(u32, Vec<String>),
+    /// A message from the bridge channel, author and text
+    ChatMessage(String, String),
+    /// The token to resume the session with after reconnecting
+    Session(String),
+    /// How many unacknowledged packets were replayed
+    Resumed(usize),
+    /// The bridge is shutting down, seconds to wait before reconnecting
+    GoingAway(u64),
+}
+
+impl OutgoingPacket {
+    /// Name of the packet, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutgoingPacket::Error(..) => "Error",
+            OutgoingPacket::ServerRun(..) => "ServerRun",
+            OutgoingPacket::AuthChallenge(_) => "AuthChallenge",
+            OutgoingPacket::Authenticated => "Authenticated",
+            OutgoingPacket::Hello(..) => "Hello",
+            OutgoingPacket::ChatMessage(..) => "ChatMessage",
+            OutgoingPacket::Session(_) => "Session",
+            OutgoingPacket::Resumed(_) => "Resumed",
+            OutgoingPacket::GoingAway(_) => "GoingAway",
+        }
+    }
+
+    /// Parse a packet sent by the bridge, as plugins do. Any `seq` is ignored
+    pub fn parse(source: &str) -> Result<Self, serde_json::Error> {
+        let PacketBase { id } = serde_json::from_str(source)?;
+        Ok(match id {
+            -1 => {
+                let ErrorPacket { message, error } = serde_json::from_str(source)?;
+                OutgoingPacket::Error(error, message)
+            }
+            0 => {
+                let ServerRunPacket { request_id, exec } = serde_json::from_str(source)?;
+                // Only protocol version 1 leaves out the request id
+                OutgoingPacket::ServerRun(request_id.unwrap_or_default(), exec)
+            }
+            1 => {
+                let AuthChallengePacket { challenge } = serde_json::from_str(source)?;
+                OutgoingPacket::AuthChallenge(challenge)
+            }
+            2 => OutgoingPacket::Authenticated,
+            3 => {
+                let HelloPacket {
+                    version,
+                    capabilities,
+                } = serde_json::from_str(source)?;
+                OutgoingPacket::Hello(version, capabilities)
+            }
+            4 => {
+                let ChatMessagePacket { author, text } = serde_json::from_str(source)?;
+                OutgoingPacket::ChatMessage(author, text)
+            }
+            5 => {
+                let SessionPacket { resume_token } = serde_json::from_str(source)?;
+                OutgoingPacket::Session(resume_token)
+            }
+            6 => {
+                let ResumedPacket { replayed } = serde_json::from_str(source)?;
+                OutgoingPacket::Resumed(replayed)
+            }
+            7 => {
+                let GoingAwayPacket { reconnect_after } = serde_json::from_str(source)?;
+                OutgoingPacket::GoingAway(reconnect_after)
+            }
+            _ => {
+                return Err(serde_json::Error::custom(format!(
+                    "Unknown packet id {}",
+                    id
+                )))
+            }
+        })
+    }
+
+    /// Serialize the packet in the shape understood by the given protocol version
+    pub fn versioned(&self, version: u32) -> Versioned<'_> {
+        Versioned(self, version)
+    }
+}
+
+/// An outgoing packet paired with the protocol version of the plugin it is sent to
+pub struct Versioned<'a>(&'a OutgoingPacket, u32);
+
+impl Serialize for Versioned<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let Versioned(packet, version) = self;
+        match packet {
+            OutgoingPacket::Error(error_type, msg) => {
+                let mut state = serializer.serialize_struct("ErrorPacket", 3)?;
+                state.serialize_field("id", &-1)?;
+                state.serialize_field("message", msg)?;
+                state.serialize_field("error", error_type)?;
+                state.end()
+            }
+            OutgoingPacket::ServerRun(_, packet) if *version < 2 => {
+                let mut state = serializer.serialize_struct("ServerRun", 2)?;
+                state.serialize_field("id", &0)?;
+                state.serialize_field("exec", packet)?;
+                state.end()
+            }
+            OutgoingPacket::ServerRun(request_id, packet) => {
+                let mut state = serializer.serialize_struct("ServerRun", 3)?;
+                state.serialize_field("id", &0)?;
+                state.serialize_field("requestId", request_id)?;
+                state.serialize_field("exec", packet)?;
+                state.end()
+            }
+            OutgoingPacket::AuthChallenge(challenge) => {
+                let mut state = serializer.serialize_struct("AuthChallenge", 2)?;
+                state.serialize_field("id", &1)?;
+                state.serialize_field("challenge", challenge)?;
+                state.end()
+            }
+            OutgoingPacket::Authenticated => {
+                let mut state = serializer.serialize_struct("Authenticated", 1)?;
+                state.serialize_field("id", &2)?;
+                state.end()
+            }
+            OutgoingPacket::Hello(version, capabilities) => {
+                let mut state = serializer.serialize_struct("Hello", 3)?;
+                state.serialize_field("id", &3)?;
+                state.serialize_field("version", version)?;
+                state.serialize_field("capabilities", capabilities)?;
+                state.end()
+            }
+            OutgoingPacket::ChatMessage(author, text) => {
+                let mut state = serializer.serialize_struct("ChatMessage", 3)?;
+                state.serialize_field("id", &4)?;
+                state.serialize_field("author", author)?;
+                state.serialize_field("text", text)?;
+                state.end()
+            }
+            OutgoingPacket::Session(resume_token) => {
+                let mut state = serializer.serialize_struct("Session", 2)?;
+                state.serialize_field("id", &5)?;
+                state.serialize_field("resumeToken", resume_token)?;
+                state.end()
+            }
+            OutgoingPacket::Resumed(replayed) => {
+                let mut state = serializer.serialize_struct("Resumed", 2)?;
+                state.serialize_field("id", &6)?;
+                state.serialize_field("replayed", replayed)?;
+                state.end()
+            }
+            OutgoingPacket::GoingAway(reconnect_after) => {
+                let mut state = serializer.serialize_struct("GoingAway", 2)?;
+                state.serialize_field("id", &7)?;
+                state.serialize_field("reconnectAfter", reconnect_after)?;
+                state.end()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PacketBase {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+struct ErrorPacket {
+    message: String,
+    error: ErrorType,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerRunPacket {
+    #[serde(default = "Default::default")]
+    request_id: Option<Uuid>,
+    exec: Exec,
+}
+
+#[derive(Deserialize)]
+struct AuthChallengePacket {
+    challenge: String,
+}
+
+#[derive(Deserialize)]
+struct HelloPacket {
+    version: u32,
+    #[serde(default = "Default::default")]
+    capabilities: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatMessagePacket {
+    author: String,
+    text: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionPacket {
+    resume_token: String,
+}
+
+#[derive(Deserialize)]
+struct ResumedPacket {
+    replayed: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GoingAwayPacket {
+    reconnect_after: u64,
+}
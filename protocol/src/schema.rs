@@ -0,0 +1,422 @@
+//! JSON Schema (draft-07) of every packet, for plugins written in other languages to generate
+//! their side of the protocol from
+//!
+//! Field types come from the Rust types through [`JsonSchema`]. `protocol/schema.json` is the
+//! committed output of `tc-discord schema`, a test fails when it no longer matches
+use crate::{
+    CommandResponsePacket, ErrorType, Exec, ServerEventKind, CAPABILITIES, PROTOCOL_VERSION,
+};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Types that know the schema of their JSON representation
+pub trait JsonSchema {
+    fn schema() -> Value;
+}
+
+impl JsonSchema for String {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl JsonSchema for bool {
+    fn schema() -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+macro_rules! unsigned_schema {
+    ($($t:ty),*) => {
+        $(impl JsonSchema for $t {
+            fn schema() -> Value {
+                json!({ "type": "integer", "minimum": 0 })
+            }
+        })*
+    };
+}
+
+unsigned_schema!(u32, u64, usize);
+
+impl JsonSchema for Uuid {
+    fn schema() -> Value {
+        json!({ "type": "string", "format": "uuid" })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for HashMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for BTreeMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn schema() -> Value {
+        json!({ "anyOf": [T::schema(), { "type": "null" }] })
+    }
+}
+
+impl JsonSchema for ErrorType {
+    fn schema() -> Value {
+        json!({ "$ref": "#/definitions/ErrorType" })
+    }
+}
+
+impl JsonSchema for Exec {
+    fn schema() -> Value {
+        json!({ "$ref": "#/definitions/Exec" })
+    }
+}
+
+/// A property of an object
+struct Field {
+    name: &'static str,
+    schema: Value,
+    required: bool,
+}
+
+fn required<T: JsonSchema>(name: &'static str) -> Field {
+    Field {
+        name,
+        schema: T::schema(),
+        required: true,
+    }
+}
+
+fn optional<T: JsonSchema>(name: &'static str) -> Field {
+    Field {
+        name,
+        schema: T::schema(),
+        required: false,
+    }
+}
+
+fn object(fields: Vec<Field>) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    for field in fields {
+        if field.required {
+            required.push(field.name);
+        }
+        properties.insert(field.name.to_string(), field.schema);
+    }
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// A packet with the given `id`. Unknown properties are ignored by both sides, so they are allowed
+fn packet(id: i32, description: &str, mut fields: Vec<Field>) -> Value {
+    fields.insert(
+        0,
+        Field {
+            name: "id",
+            schema: json!({ "const": id }),
+            required: true,
+        },
+    );
+    let mut packet = object(fields);
+    packet["description"] = description.into();
+    packet
+}
+
+/// An outgoing packet, which carries a `seq` on resumable sessions
+fn outgoing(id: i32, description: &str, mut fields: Vec<Field>) -> Value {
+    fields.push(optional::<u64>("seq"));
+    packet(id, description, fields)
+}
+
+fn server_event(kind: &str, mut fields: Vec<Field>) -> Value {
+    fields.insert(
+        0,
+        Field {
+            name: "kind",
+            schema: json!({ "const": kind }),
+            required: true,
+        },
+    );
+    packet(7, &format!("Server event `{}`", kind), fields)
+}
+
+/// Every packet plugins send, by name
+fn incoming_packets() -> Vec<(&'static str, Value)> {
+    vec![
+        (
+            "SetName",
+            packet(
+                0,
+                "Set the name of the server",
+                vec![required::<String>("name")],
+            ),
+        ),
+        (
+            "SetControlChannel",
+            packet(
+                1,
+                "Set the discord channel the server is controlled from",
+                vec![required::<String>("ctrlChannelId")],
+            ),
+        ),
+        (
+            "CommandResponse",
+            packet(
+                2,
+                "Report the outcome of a ServerRun packet, `run` holds the output of each command in order",
+                CommandResponsePacket::fields(),
+            ),
+        ),
+        (
+            "Auth",
+            packet(
+                3,
+                "Answer the auth challenge with the hex encoded HMAC-SHA256 of it, keyed with the shared secret",
+                vec![required::<String>("response")],
+            ),
+        ),
+        (
+            "Hello",
+            packet(
+                4,
+                "Agree on a protocol version, should be the first packet a plugin sends",
+                vec![
+                    required::<u32>("version"),
+                    optional::<Vec<String>>("capabilities"),
+                ],
+            ),
+        ),
+        (
+            "ChatMessage",
+            packet(
+                5,
+                "Relay something a player said in game to the bridge channel",
+                vec![
+                    required::<String>("player"),
+                    required::<String>("text"),
+                    optional::<Option<String>>("avatar"),
+                ],
+            ),
+        ),
+        (
+            "SetBridgeChannel",
+            packet(
+                6,
+                "Set the discord channel in game chat is bridged with, must differ from the control channel",
+                vec![required::<String>("bridgeChannelId")],
+            ),
+        ),
+        (
+            "ServerEvent",
+            json!({
+                "description": "Report something that happened on the server",
+                "oneOf": ServerEventKind::schemas(),
+            }),
+        ),
+        (
+            "SetServerId",
+            packet(
+                8,
+                "Identify the server across reconnects, should be sent before SetName",
+                vec![required::<String>("serverId")],
+            ),
+        ),
+        (
+            "Ack",
+            packet(
+                9,
+                "Acknowledge every packet up to and including `seq` on a resumable session",
+                vec![required::<u64>("seq")],
+            ),
+        ),
+        (
+            "Resume",
+            packet(
+                10,
+                "Pick up a session after reconnecting, sent instead of SetName and SetControlChannel",
+                vec![required::<String>("resumeToken")],
+            ),
+        ),
+        (
+            "SetTags",
+            packet(
+                11,
+                "Announce key/value tags selectors can target, replacing any sent before",
+                vec![required::<BTreeMap<String, String>>("tags")],
+            ),
+        ),
+    ]
+}
+
+/// Every packet the bridge sends, by name
+fn outgoing_packets() -> Vec<(&'static str, Value)> {
+    vec![
+        (
+            "Error",
+            outgoing(
+                -1,
+                "Something the plugin sent was wrong",
+                vec![
+                    required::<String>("message"),
+                    required::<ErrorType>("error"),
+                ],
+            ),
+        ),
+        (
+            "ServerRun",
+            outgoing(
+                0,
+                "Run commands on the server, `requestId` is left out for protocol version 1",
+                vec![optional::<Uuid>("requestId"), required::<Exec>("exec")],
+            ),
+        ),
+        (
+            "AuthChallenge",
+            outgoing(
+                1,
+                "Sent on connect, answered with an Auth packet",
+                vec![required::<String>("challenge")],
+            ),
+        ),
+        (
+            "Authenticated",
+            outgoing(2, "The Auth packet was accepted", vec![]),
+        ),
+        (
+            "Hello",
+            outgoing(
+                3,
+                "The protocol version and capabilities the bridge agreed to",
+                vec![
+                    required::<u32>("version"),
+                    required::<Vec<String>>("capabilities"),
+                ],
+            ),
+        ),
+        (
+            "ChatMessage",
+            outgoing(
+                4,
+                "A message from the bridge channel",
+                vec![required::<String>("author"), required::<String>("text")],
+            ),
+        ),
+        (
+            "Session",
+            outgoing(
+                5,
+                "The token to resume the session with after reconnecting",
+                vec![required::<String>("resumeToken")],
+            ),
+        ),
+        (
+            "Resumed",
+            outgoing(
+                6,
+                "The session was resumed, with how many unacknowledged packets were replayed",
+                vec![required::<usize>("replayed")],
+            ),
+        ),
+        (
+            "GoingAway",
+            outgoing(
+                7,
+                "The bridge is shutting down, seconds to wait before reconnecting. Only sent from protocol version 3",
+                vec![required::<u64>("reconnectAfter")],
+            ),
+        ),
+    ]
+}
+
+impl CommandResponsePacket {
+    fn fields() -> Vec<Field> {
+        vec![
+            required::<Uuid>("requestId"),
+            optional::<Vec<String>>("run"),
+            optional::<HashMap<String, String>>("query"),
+            optional::<HashMap<String, bool>>("set"),
+        ]
+    }
+}
+
+impl Exec {
+    fn fields() -> Vec<Field> {
+        vec![
+            optional::<Vec<String>>("run"),
+            optional::<Vec<String>>("query"),
+            optional::<HashMap<String, String>>("set"),
+        ]
+    }
+}
+
+impl ServerEventKind {
+    fn schemas() -> Vec<Value> {
+        vec![
+            server_event("playerJoined", vec![required::<String>("player")]),
+            server_event("playerLeft", vec![required::<String>("player")]),
+            server_event(
+                "playerDied",
+                vec![
+                    required::<String>("player"),
+                    optional::<Option<String>>("message"),
+                ],
+            ),
+            server_event(
+                "playerKicked",
+                vec![
+                    required::<String>("player"),
+                    optional::<Option<String>>("reason"),
+                ],
+            ),
+            server_event("starting", vec![]),
+            server_event("stopping", vec![]),
+            server_event("crashed", vec![optional::<Option<String>>("reason")]),
+        ]
+    }
+}
+
+/// The whole protocol. Incoming and outgoing packet ids overlap, so each direction has its own
+/// root in `definitions`
+pub fn schema() -> Value {
+    let mut definitions = Map::new();
+    let mut directions = Map::new();
+    for (direction, packets) in [
+        ("Incoming", incoming_packets()),
+        ("Outgoing", outgoing_packets()),
+    ] {
+        let mut refs = vec![];
+        for (name, mut schema) in packets {
+            let key = format!("{}{}", direction, name);
+            schema["title"] = name.into();
+            refs.push(json!({ "$ref": format!("#/definitions/{}", key) }));
+            definitions.insert(key, schema);
+        }
+        directions.insert(format!("{}Packet", direction), json!({ "oneOf": refs }));
+    }
+    definitions.extend(directions);
+    definitions.insert(
+        "ErrorType".to_string(),
+        json!({ "type": "string", "enum": ErrorType::ALL }),
+    );
+    definitions.insert("Exec".to_string(), object(Exec::fields()));
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "tc-discord plugin protocol",
+        "description": format!(
+            "Protocol version {}, optional capabilities: {}",
+            PROTOCOL_VERSION,
+            CAPABILITIES.join(", ")
+        ),
+        "definitions": definitions,
+    })
+}
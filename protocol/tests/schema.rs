@@ -0,0 +1,341 @@
+//! Keeps `schema.json` in step with the packet types, by validating packets built from the types
+//! against it. Examples are checked with every object closed to fields the schema doesn't list,
+//! so a field added to a packet but not to the schema fails
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use tc_discord_protocol::{
+    schema::schema, ChatMessagePacket, CommandResponsePacket, ErrorType, Exec, IncomingPacket,
+    OutgoingPacket, ServerEventKind, PROTOCOL_VERSION,
+};
+use uuid::Uuid;
+
+/// Check `value` against `schema`, supporting the keywords our schema uses
+fn validate(root: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+    if schema == &Value::Bool(false) {
+        return Err(format!("{} is not allowed", value));
+    }
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/definitions/");
+        return validate(root, &root["definitions"][name], value);
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("expected {}, got {}", expected, value));
+        }
+    }
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            return Err(format!("{} is not one of {:?}", value, allowed));
+        }
+    }
+    if let Some(options) = schema["oneOf"].as_array() {
+        let matching = options
+            .iter()
+            .filter(|option| validate(root, option, value).is_ok())
+            .count();
+        if matching != 1 {
+            return Err(format!("{} matches {} of oneOf", value, matching));
+        }
+    }
+    if let Some(options) = schema["anyOf"].as_array() {
+        if !options
+            .iter()
+            .any(|option| validate(root, option, value).is_ok())
+        {
+            return Err(format!("{} matches none of anyOf", value));
+        }
+    }
+
+    match schema["type"].as_str() {
+        Some("object") => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| format!("{} is not an object", value))?;
+            for required in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(required.as_str().unwrap()) {
+                    return Err(format!("{} is missing {}", value, required));
+                }
+            }
+            for (key, field) in object {
+                match schema["properties"].get(key) {
+                    Some(property) => validate(root, property, field),
+                    None => match schema.get("additionalProperties") {
+                        Some(additional) => validate(root, additional, field),
+                        None => Ok(()),
+                    },
+                }
+                .map_err(|err| format!("{}: {}", key, err))?;
+            }
+        }
+        Some("array") => {
+            let items = value
+                .as_array()
+                .ok_or_else(|| format!("{} is not an array", value))?;
+            for item in items {
+                validate(root, &schema["items"], item)?;
+            }
+        }
+        Some("string") => {
+            let string = value
+                .as_str()
+                .ok_or_else(|| format!("{} is not a string", value))?;
+            if schema["format"] == "uuid" && Uuid::parse_str(string).is_err() {
+                return Err(format!("{} is not a uuid", value));
+            }
+        }
+        Some("integer") => {
+            if !value.is_i64() && !value.is_u64() {
+                return Err(format!("{} is not an integer", value));
+            }
+            if let Some(minimum) = schema["minimum"].as_i64() {
+                if value.as_i64().is_some_and(|value| value < minimum) {
+                    return Err(format!("{} is less than {}", value, minimum));
+                }
+            }
+        }
+        Some("boolean") if !value.is_boolean() => {
+            return Err(format!("{} is not a boolean", value))
+        }
+        Some("null") if !value.is_null() => return Err(format!("{} is not null", value)),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `schema` with `additionalProperties: false` on every object that lists its properties and
+/// doesn't say what else it takes
+fn strict(schema: &Value) -> Value {
+    match schema {
+        Value::Object(object) => {
+            let mut closed = object
+                .iter()
+                .map(|(key, value)| (key.clone(), strict(value)))
+                .collect::<serde_json::Map<String, Value>>();
+            if closed.contains_key("properties") && !closed.contains_key("additionalProperties") {
+                closed.insert("additionalProperties".to_string(), Value::Bool(false));
+            }
+            Value::Object(closed)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(strict).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Every packet id the definitions under `direction` accept
+fn ids(root: &Value, direction: &str) -> Vec<i64> {
+    let mut ids = vec![];
+    for (name, definition) in root["definitions"].as_object().unwrap() {
+        if !name.starts_with(direction) || name.ends_with("Packet") {
+            continue;
+        }
+        let options = match definition["oneOf"].as_array() {
+            Some(options) => options.clone(),
+            None => vec![definition.clone()],
+        };
+        ids.extend(
+            options
+                .iter()
+                .filter_map(|option| option["properties"]["id"]["const"].as_i64()),
+        );
+    }
+    ids
+}
+
+fn incoming_examples() -> Vec<IncomingPacket> {
+    let mut examples = vec![
+        IncomingPacket::SetName("lobby".to_string()),
+        IncomingPacket::SetControlChannel("100".to_string()),
+        IncomingPacket::CommandResponse(CommandResponsePacket {
+            request_id: Uuid::from_u128(1),
+            run: vec!["said hi".to_string()],
+            query: HashMap::from([("players".to_string(), "3".to_string())]),
+            set: HashMap::from([("weather".to_string(), true)]),
+        }),
+        IncomingPacket::Auth("00ff".to_string()),
+        IncomingPacket::Hello(PROTOCOL_VERSION, vec!["chat".to_string()]),
+        IncomingPacket::ChatMessage(ChatMessagePacket {
+            player: "steve".to_string(),
+            text: "hi".to_string(),
+            avatar: None,
+        }),
+        IncomingPacket::SetBridgeChannel("200".to_string()),
+        IncomingPacket::SetServerId("lobby-1".to_string()),
+        IncomingPacket::Ack(3),
+        IncomingPacket::Resume("token".to_string()),
+        IncomingPacket::SetTags(BTreeMap::from([("region".to_string(), "eu".to_string())])),
+    ];
+    let player = || "steve".to_string();
+    examples.extend(
+        vec![
+            ServerEventKind::PlayerJoined { player: player() },
+            ServerEventKind::PlayerLeft { player: player() },
+            ServerEventKind::PlayerDied {
+                player: player(),
+                message: Some("fell".to_string()),
+            },
+            ServerEventKind::PlayerKicked {
+                player: player(),
+                reason: None,
+            },
+            ServerEventKind::Starting,
+            ServerEventKind::Stopping,
+            ServerEventKind::Crashed { reason: None },
+        ]
+        .into_iter()
+        .map(IncomingPacket::ServerEvent),
+    );
+    examples
+}
+
+fn outgoing_examples() -> Vec<OutgoingPacket> {
+    let mut examples = ErrorType::ALL
+        .iter()
+        .map(|error| OutgoingPacket::Error(error.clone(), "wrong".to_string()))
+        .collect::<Vec<OutgoingPacket>>();
+    examples.extend(vec![
+        OutgoingPacket::ServerRun(
+            Uuid::from_u128(1),
+            Exec {
+                run: vec!["say hi".to_string()],
+                query: vec!["players".to_string()],
+                set: HashMap::from([("weather".to_string(), "rain".to_string())]),
+            },
+        ),
+        OutgoingPacket::AuthChallenge("challenge".to_string()),
+        OutgoingPacket::Authenticated,
+        OutgoingPacket::Hello(PROTOCOL_VERSION, vec!["chat".to_string()]),
+        OutgoingPacket::ChatMessage("alex".to_string(), "hello".to_string()),
+        OutgoingPacket::Session("token".to_string()),
+        OutgoingPacket::Resumed(2),
+        OutgoingPacket::GoingAway(5),
+    ]);
+    examples
+}
+
+#[test]
+fn committed_schema_is_up_to_date() {
+    let committed: Value = serde_json::from_str(include_str!("../schema.json")).unwrap();
+    assert!(
+        committed == schema(),
+        "schema.json is out of date, regenerate it with `cargo run -- schema > protocol/schema.json`"
+    );
+}
+
+#[test]
+fn incoming_packets_match_the_schema() {
+    let root = strict(&schema());
+    for packet in incoming_examples() {
+        let value = serde_json::to_value(&packet).unwrap();
+        let definition = &root["definitions"][format!("Incoming{}", packet.name())];
+        assert!(definition.is_object(), "No schema for {}", packet.name());
+        validate(&root, definition, &value).unwrap();
+        validate(&root, &root["definitions"]["IncomingPacket"], &value).unwrap();
+
+        let parsed = IncomingPacket::from(value.to_string());
+        assert_eq!(parsed.name(), packet.name(), "{}", value);
+    }
+}
+
+#[test]
+fn outgoing_packets_match_the_schema() {
+    let root = strict(&schema());
+    for packet in outgoing_examples() {
+        let definition = &root["definitions"][format!("Outgoing{}", packet.name())];
+        assert!(definition.is_object(), "No schema for {}", packet.name());
+        for version in 1..=PROTOCOL_VERSION {
+            let mut value = serde_json::to_value(packet.versioned(version)).unwrap();
+            validate(&root, definition, &value).unwrap();
+            value["seq"] = 1.into();
+            validate(&root, &root["definitions"]["OutgoingPacket"], &value).unwrap();
+
+            let parsed = OutgoingPacket::parse(&value.to_string()).unwrap();
+            assert_eq!(parsed.name(), packet.name(), "{}", value);
+        }
+    }
+}
+
+#[test]
+fn fields_missing_from_the_schema_are_caught() {
+    let root = strict(&schema());
+    let mut value = serde_json::to_value(IncomingPacket::SetName("lobby".to_string())).unwrap();
+    value["nickname"] = "lobby".into();
+    assert!(validate(&root, &root["definitions"]["IncomingSetName"], &value).is_err());
+}
+
+/// Checks `ErrorType::ALL` lists exactly `$variant`s, in order. The match has no wildcard arm, so
+/// a new error type fails to compile here until it is listed, and then fails until it is in `ALL`
+macro_rules! assert_error_types {
+    ($($variant:ident),* $(,)?) => {{
+        fn listed(error: &ErrorType) {
+            match error {
+                $(ErrorType::$variant => {})*
+            }
+        }
+        ErrorType::ALL.iter().for_each(listed);
+        assert_eq!(ErrorType::ALL, &[$(ErrorType::$variant),*]);
+    }};
+}
+
+#[test]
+fn every_error_type_is_in_all() {
+    assert_error_types!(
+        PacketInvalidID,
+        PacketDeserializationError,
+        UnknownRequestID,
+        Unauthorized,
+        IncompatibleVersion,
+        InvalidBridgeChannel,
+        NoBridgeChannel,
+        NameConflict,
+        ResumeFailed,
+        Kicked,
+    );
+}
+
+#[test]
+fn every_packet_id_has_a_schema() {
+    let root = schema();
+    let (incoming, outgoing) = (ids(&root, "Incoming"), ids(&root, "Outgoing"));
+    for id in -10..100 {
+        let packet = json!({ "id": id }).to_string();
+        if !matches!(
+            IncomingPacket::from(packet.clone()),
+            IncomingPacket::InvalidID
+        ) {
+            assert!(
+                incoming.contains(&id),
+                "Incoming packet {} has no schema",
+                id
+            );
+        }
+        let unknown = matches!(
+            OutgoingPacket::parse(&packet),
+            Err(err) if err.to_string().starts_with("Unknown packet id")
+        );
+        if !unknown {
+            assert!(
+                outgoing.contains(&id),
+                "Outgoing packet {} has no schema",
+                id
+            );
+        }
+    }
+}
+
+#[test]
+fn malformed_packets_do_not_match_the_schema() {
+    let root = schema();
+    let incoming = &root["definitions"]["IncomingPacket"];
+    for packet in [
+        json!({ "id": 0 }),
+        json!({ "id": 0, "name": 1 }),
+        json!({ "id": 2, "requestId": "not a uuid" }),
+        json!({ "id": 7, "kind": "exploded" }),
+        json!({ "id": 9, "seq": -1 }),
+        json!({ "id": 42 }),
+    ] {
+        assert!(validate(&root, incoming, &packet).is_err(), "{}", packet);
+    }
+    let error = json!({ "id": -1, "message": "wrong", "error": "NoSuchError" });
+    assert!(validate(&root, &root["definitions"]["OutgoingPacket"], &error).is_err());
+}
@@ -0,0 +1,351 @@
+//! HTTP API for managing connected servers without going through discord
+//!
+//! Every request needs `Authorization: Bearer <token>`, every response is JSON
+use crate::{
+    audit::{self, AuditLog, CommandRecord, ResponseRecord},
+    config::Secret,
+    discord::{
+        dispatch_server_command, permissions::Permissions, server_command::ServerCommand,
+        DispatchError,
+    },
+    ws::{Am, LifecycleEvent, ServerDetails, ServerEvent, ServerInfo, WsClient, WsManager},
+};
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use twilight_model::id::ChannelId;
+use uuid::Uuid;
+
+/// How many events `/events` remembers
+const RECENT_EVENTS: usize = 100;
+/// How many audit log entries are returned per page of `/audit`
+const AUDIT_PAGE_SIZE: usize = 20;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address the API binds to, keep it off the public internet
+    pub bind: SocketAddr,
+    /// Token clients send as `Authorization: Bearer <token>`
+    pub token: Secret,
+}
+
+/// An event the API remembers, newest last
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct RecentEvent {
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    #[serde(flatten)]
+    event: AnyEvent,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum AnyEvent {
+    Lifecycle(LifecycleEvent),
+    Server(ServerEvent),
+}
+
+/// Body of `POST /commands`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommandRequest {
+    /// Control channel the `on` selector is resolved in
+    channel_id: ChannelId,
+    #[serde(flatten)]
+    command: ServerCommand,
+}
+
+/// A command and the responses to it, as returned by `/audit`
+#[derive(Serialize)]
+struct AuditEntry {
+    command: CommandRecord,
+    responses: Vec<ResponseRecord>,
+}
+
+struct State {
+    ws_mgr: Am<WsManager>,
+    audit: Arc<AuditLog>,
+    token: Secret,
+    // Only held to push or copy, never across an await
+    events: Mutex<VecDeque<RecentEvent>>,
+}
+
+/// Serve the API until the listener fails
+pub async fn serve(
+    config: AdminConfig,
+    ws_mgr: Am<WsManager>,
+    audit: Arc<AuditLog>,
+) -> Result<(), hyper::Error> {
+    let (lifecycle_events, server_events) = {
+        let ws_mgr = ws_mgr.lock().await;
+        (ws_mgr.subscribe(), ws_mgr.subscribe_server_events())
+    };
+    let state = Arc::new(State {
+        ws_mgr,
+        audit,
+        token: config.token,
+        events: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+    });
+    tokio::spawn(remember_events(
+        state.clone(),
+        lifecycle_events,
+        server_events,
+    ));
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+
+    info!("Admin API listening on {}", config.bind);
+    Server::bind(&config.bind).serve(make_service).await
+}
+
+/// Keep the last [`RECENT_EVENTS`] events around for `/events`
+async fn remember_events(
+    state: Arc<State>,
+    mut lifecycle_events: Receiver<LifecycleEvent>,
+    mut server_events: Receiver<ServerEvent>,
+) {
+    loop {
+        let event = tokio::select! {
+            event = lifecycle_events.recv() => event.map(AnyEvent::Lifecycle),
+            event = server_events.recv() => event.map(AnyEvent::Server),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Admin API missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let mut events = state.events.lock().unwrap();
+        if events.len() == RECENT_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(RecentEvent {
+            timestamp: audit::now(),
+            event,
+        });
+    }
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if !authorized(&state.token, &req) {
+        return Ok(error(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid API token",
+        ));
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_matches('/').to_string();
+    let segments = path.split('/').collect::<Vec<&str>>();
+    let response = match (method, segments.as_slice()) {
+        (Method::GET, ["servers"]) => list_servers(&state).await,
+        (Method::GET, ["servers", uuid]) => server_details(&state, uuid).await,
+        (Method::POST, ["servers", uuid, "kick"]) => kick(&state, uuid).await,
+        (Method::POST, ["commands"]) => send_command(&state, req).await,
+        (Method::GET, ["audit"]) => read_audit(&state, req.uri().query().unwrap_or("")).await,
+        (Method::GET, ["events"]) => {
+            let events = state.events.lock().unwrap().clone();
+            json(StatusCode::OK, &events)
+        }
+        (_, ["servers"])
+        | (_, ["servers", _])
+        | (_, ["servers", _, "kick"])
+        | (_, ["commands"])
+        | (_, ["audit"])
+        | (_, ["events"]) => error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        _ => error(StatusCode::NOT_FOUND, "No such endpoint"),
+    };
+
+    Ok(response)
+}
+
+/// Whether `req` carries the API token, compared in constant time
+fn authorized(token: &Secret, req: &Request<Body>) -> bool {
+    let given = match req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+    {
+        Some(given) => given.as_bytes(),
+        None => return false,
+    };
+    let expected = token.expose().as_bytes();
+
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn list_servers(state: &State) -> Response<Body> {
+    let servers = state
+        .ws_mgr
+        .lock()
+        .await
+        .get_connected()
+        .into_iter()
+        .map(|(info, _)| info)
+        .collect::<Vec<ServerInfo>>();
+    json(StatusCode::OK, &servers)
+}
+
+async fn server_details(state: &State, uuid: &str) -> Response<Body> {
+    match find_server(state, uuid).await {
+        Ok(server) => {
+            let details: ServerDetails = server.lock().await.details();
+            json(StatusCode::OK, &details)
+        }
+        Err(response) => response,
+    }
+}
+
+async fn kick(state: &State, uuid: &str) -> Response<Body> {
+    match find_server(state, uuid).await {
+        Ok(server) => {
+            server.lock().await.kick();
+            info!("Kicked {} through the admin API", uuid);
+            json(StatusCode::OK, &json!({ "kicked": uuid }))
+        }
+        Err(response) => response,
+    }
+}
+
+async fn send_command(state: &State, req: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let request: CommandRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+
+    // No invoker, holding the API token allows anything
+    match dispatch_server_command(
+        &state.ws_mgr,
+        &Permissions::default(),
+        &state.audit,
+        None,
+        &request.command,
+        request.channel_id,
+        None,
+    )
+    .await
+    {
+        Ok(dispatched) if request.command.dry_run => json(
+            StatusCode::OK,
+            &json!({ "dryRun": true, "servers": dispatched.servers }),
+        ),
+        Ok(dispatched) => {
+            let sent = dispatched.sent();
+            // Some servers missed it, or all of them did
+            let status = match (sent.is_empty(), dispatched.failed.is_empty()) {
+                (_, true) => StatusCode::OK,
+                (false, false) => StatusCode::MULTI_STATUS,
+                (true, false) => StatusCode::BAD_GATEWAY,
+            };
+            json(
+                status,
+                &json!({ "sent": sent, "failed": dispatched.failed }),
+            )
+        }
+        Err(DispatchError::InvalidSelector(err)) => error(StatusCode::BAD_REQUEST, &err),
+        Err(DispatchError::NoServers) => error(
+            StatusCode::NOT_FOUND,
+            &format!("No servers matched the query {}", request.command.on),
+        ),
+        Err(DispatchError::PermissionDenied(denied)) => error(
+            StatusCode::FORBIDDEN,
+            &format!("Not allowed to send this command to {}", denied.join(", ")),
+        ),
+        // Only commands confirmed from discord are checked against their targets
+        Err(DispatchError::TargetsChanged) => {
+            error(StatusCode::CONFLICT, "The matching servers changed")
+        }
+    }
+}
+
+/// `?channel=<id>&page=<n>`, the same pages `/audit` shows in discord
+async fn read_audit(state: &State, query: &str) -> Response<Body> {
+    let mut channel = None;
+    let mut page = 1;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "channel" => channel = value.parse::<u64>().ok(),
+            "page" => page = value.parse::<usize>().unwrap_or(1).max(1),
+            _ => {}
+        }
+    }
+    let channel = match channel {
+        Some(channel) => channel,
+        None => return error(StatusCode::BAD_REQUEST, "channel must be a channel id"),
+    };
+
+    match state
+        .audit
+        .recent_commands(channel, (page - 1) * AUDIT_PAGE_SIZE, AUDIT_PAGE_SIZE)
+        .await
+    {
+        Ok(records) => json(
+            StatusCode::OK,
+            &records
+                .into_iter()
+                .map(|(command, responses)| AuditEntry { command, responses })
+                .collect::<Vec<AuditEntry>>(),
+        ),
+        Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+/// The connected server with the uuid in the path, or the response explaining why there isn't one
+async fn find_server(state: &State, uuid: &str) -> Result<Am<WsClient>, Response<Body>> {
+    let uuid =
+        Uuid::parse_str(uuid).map_err(|_| error(StatusCode::BAD_REQUEST, "Invalid server uuid"))?;
+    state
+        .ws_mgr
+        .lock()
+        .await
+        .get_connection_by_uuid(uuid)
+        .map(|(_, server)| server)
+        .ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                "No server with that uuid is connected",
+            )
+        })
+}
+
+fn json(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap_or_default()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &json!({ "error": message }))
+}
@@ -0,0 +1,182 @@
+//! Append-only record of every command sent to game servers, stored as JSON lines
+use anyhow::anyhow;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum AuditRecord {
+    Command(CommandRecord),
+    Response(ResponseRecord),
+}
+
+/// A server a command was resolved to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditServer {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+/// What happened to a command once it was resolved
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum Outcome {
+    /// Sent to every resolved server, except the ones in `failed`
+    Sent {
+        failed: Vec<String>,
+    },
+    /// The `on` selector didn't parse
+    InvalidSelector {
+        error: String,
+    },
+    NoServers,
+    PermissionDenied {
+        denied: Vec<String>,
+    },
+    /// Held back until someone confirms it, `servers` are the ones shown for confirming
+    Held {
+        reason: String,
+    },
+    /// A held command was cancelled by `by`
+    Cancelled {
+        by: u64,
+    },
+    /// Nobody confirmed a held command in time
+    Expired,
+    /// Confirmed, but not sent because the servers it matches changed after it was held back
+    TargetsChanged,
+}
+
+/// A command someone asked to be sent to one or more servers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandRecord {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub request_id: Uuid,
+    /// 0 for commands sent through the admin API
+    pub user_id: u64,
+    pub channel_id: u64,
+    pub on: String,
+    pub run: Vec<String>,
+    pub query: Vec<String>,
+    pub set: HashMap<String, String>,
+    pub servers: Vec<AuditServer>,
+    pub outcome: Outcome,
+}
+
+/// A server's reply to a command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseRecord {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub request_id: Uuid,
+    pub server: AuditServer,
+    pub run: Vec<String>,
+    pub query: HashMap<String, String>,
+    pub set: HashMap<String, bool>,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    // Keeps lines from concurrent writers from interleaving
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append a record to the log, failures are logged rather than returned so that auditing
+    /// never stops a command from going through
+    pub async fn record(&self, record: AuditRecord) {
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Error serializing audit record, {}", err);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let _lock = self.lock.lock().await;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await;
+        let result = match file {
+            Ok(mut file) => file.write_all(line.as_bytes()).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            error!("Error writing to {}, {}", self.path.display(), err);
+        }
+    }
+
+    /// Commands sent from `channel_id`, newest first, along with any responses to them
+    pub async fn recent_commands(
+        &self,
+        channel_id: u64,
+        skip: usize,
+        take: usize,
+    ) -> anyhow::Result<Vec<(CommandRecord, Vec<ResponseRecord>)>> {
+        let source = {
+            let _lock = self.lock.lock().await;
+            match fs::read_to_string(&self.path).await {
+                Ok(source) => source,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+                Err(err) => return Err(anyhow!("Could not read {}: {}", self.path.display(), err)),
+            }
+        };
+
+        let mut commands = vec![];
+        let mut responses: HashMap<Uuid, Vec<ResponseRecord>> = HashMap::new();
+        for line in source.lines().filter(|line| !line.is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(AuditRecord::Command(command)) if command.channel_id == channel_id => {
+                    commands.push(command)
+                }
+                Ok(AuditRecord::Command(_)) => {}
+                Ok(AuditRecord::Response(response)) => responses
+                    .entry(response.request_id)
+                    .or_default()
+                    .push(response),
+                Err(err) => error!("Skipping invalid audit record, {}", err),
+            }
+        }
+
+        Ok(commands
+            .into_iter()
+            .rev()
+            .skip(skip)
+            .take(take)
+            .map(|command| {
+                let responses = responses.remove(&command.request_id).unwrap_or_default();
+                (command, responses)
+            })
+            .collect())
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,415 @@
+//! Simulates game servers connecting to the bridge, for development and load testing
+//!
+//! Each simulated server authenticates, registers as `<prefix>-<n>` in the given control channel
+//! and answers `ServerRun` packets from a script. Malformed packets can be mixed in to exercise the
+//! bridge's error handling. Stats are printed periodically and when the simulation ends
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env,
+    error::Error,
+    fs, process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_tungstenite::tungstenite::Message;
+
+const USAGE: &str = "Usage: tc-discord-sim --ctrl-channel <id> [--url <ws url>] [--servers <n>] \
+[--prefix <name>] [--secret <secret>] [--script <path>] [--malformed <seconds>] \
+[--duration <seconds>] [--report <seconds>]";
+/// Stands in for timers that never fire
+const FOREVER: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+struct Options {
+    url: String,
+    servers: usize,
+    ctrl_channel_id: String,
+    prefix: String,
+    /// Defaults to `PLUGIN_SECRET`, like the bridge's own config
+    secret: String,
+    script: Script,
+    /// How often each server sends a malformed packet, never if unset
+    malformed: Option<Duration>,
+    /// How long to run for, until ctrl-c if unset
+    duration: Option<Duration>,
+    report: Duration,
+}
+
+impl Options {
+    fn from_args() -> Result<Self, String> {
+        let mut url = "ws://127.0.0.1:8080".to_string();
+        let mut servers = 1;
+        let mut ctrl_channel_id = None;
+        let mut prefix = "sim".to_string();
+        let mut secret = env::var("PLUGIN_SECRET").ok();
+        let mut script = Script::default();
+        let mut malformed = None;
+        let mut duration = None;
+        let mut report = Duration::from_secs(10);
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{}, {} needs a value", USAGE, arg))?;
+            match arg.as_str() {
+                "--url" => url = value,
+                "--servers" => servers = number(&arg, &value)?,
+                "--ctrl-channel" => ctrl_channel_id = Some(value),
+                "--prefix" => prefix = value,
+                "--secret" => secret = Some(value),
+                "--script" => script = Script::load(&value)?,
+                "--malformed" => {
+                    malformed = Some(Duration::from_secs(number::<u64>(&arg, &value)?.max(1)))
+                }
+                "--duration" => duration = Some(Duration::from_secs(number(&arg, &value)?)),
+                "--report" => report = Duration::from_secs(number::<u64>(&arg, &value)?.max(1)),
+                _ => return Err(format!("{}, unexpected argument {}", USAGE, arg)),
+            }
+        }
+
+        Ok(Self {
+            url,
+            servers,
+            ctrl_channel_id: ctrl_channel_id
+                .ok_or_else(|| format!("{}, --ctrl-channel is required", USAGE))?,
+            prefix,
+            secret: secret.ok_or_else(|| format!("{}, set --secret or PLUGIN_SECRET", USAGE))?,
+            script,
+            malformed,
+            duration,
+            report,
+        })
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{}, {} must be a number", USAGE, arg))
+}
+
+/// Canned responses, commands and queries not listed get a generic answer
+/// ```yaml
+/// run:
+///   list: "There are 3 players online"
+/// query:
+///   difficulty: hard
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Script {
+    #[serde(default = "Default::default")]
+    run: HashMap<String, String>,
+    #[serde(default = "Default::default")]
+    query: HashMap<String, String>,
+}
+
+impl Script {
+    fn load(path: &str) -> Result<Self, String> {
+        let source =
+            fs::read_to_string(path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        serde_yaml::from_str(&source).map_err(|err| format!("Invalid script {}: {}", path, err))
+    }
+
+    /// The `CommandResponse` packet answering `server_run`
+    fn respond(&self, server_run: &Value) -> Value {
+        let exec = &server_run["exec"];
+        let run = strings(&exec["run"])
+            .map(|command| {
+                self.run
+                    .get(command)
+                    .cloned()
+                    .unwrap_or_else(|| format!("Ran {}", command))
+            })
+            .collect::<Vec<String>>();
+        let query = strings(&exec["query"])
+            .map(|key| {
+                let value = self
+                    .query
+                    .get(key)
+                    .cloned()
+                    .unwrap_or_else(|| key.to_string());
+                (key.to_string(), Value::from(value))
+            })
+            .collect::<Map<String, Value>>();
+        let set = exec["set"]
+            .as_object()
+            .map(|set| {
+                set.keys()
+                    .map(|key| (key.clone(), Value::from(true)))
+                    .collect::<Map<String, Value>>()
+            })
+            .unwrap_or_default();
+
+        json!({
+            "id": 2,
+            "requestId": server_run["requestId"],
+            "run": run,
+            "query": query,
+            "set": set,
+        })
+    }
+}
+
+fn strings(value: &Value) -> impl Iterator<Item = &str> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str())
+}
+
+/// Packets the bridge should refuse, sent in turn
+const MALFORMED: &[&str] = &[
+    // Unknown id, answered with PacketInvalidID
+    r#"{"id": 42}"#,
+    // Not JSON, answered with PacketDeserializationError
+    "not a packet",
+    // SetName without a name, answered with PacketDeserializationError
+    r#"{"id": 0}"#,
+];
+
+#[derive(Default)]
+struct Stats {
+    connected: usize,
+    disconnected: usize,
+    failed: usize,
+    commands: usize,
+    malformed: usize,
+    /// Error packets received, by error type
+    errors: BTreeMap<String, usize>,
+    /// From connecting to being authenticated
+    handshakes: Vec<Duration>,
+    /// From sending a malformed packet to the error answering it
+    error_round_trips: Vec<Duration>,
+}
+
+impl Stats {
+    fn print(&self, elapsed: Duration) {
+        println!("--- {}s", elapsed.as_secs());
+        println!(
+            "servers: {} connected, {} disconnected, {} failed to connect",
+            self.connected, self.disconnected, self.failed
+        );
+        println!(
+            "packets: {} commands answered, {} malformed sent",
+            self.commands, self.malformed
+        );
+        for (error, count) in &self.errors {
+            println!("errors: {} {}", count, error);
+        }
+        print_latency("handshake", &self.handshakes);
+        print_latency("error round trip", &self.error_round_trips);
+    }
+}
+
+fn print_latency(name: &str, samples: &[Duration]) {
+    if samples.is_empty() {
+        return;
+    }
+    let mut samples = samples.to_vec();
+    samples.sort();
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100].as_secs_f64() * 1000.0;
+    let mean = samples.iter().sum::<Duration>().as_secs_f64() * 1000.0 / samples.len() as f64;
+    println!(
+        "{}: n={} min={:.1}ms mean={:.1}ms p50={:.1}ms p99={:.1}ms max={:.1}ms",
+        name,
+        samples.len(),
+        percentile(0),
+        mean,
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+}
+
+/// Connect and authenticate
+async fn connect(
+    options: &Options,
+    stats: &Mutex<Stats>,
+) -> Result<Socket, Box<dyn Error + Send + Sync>> {
+    let started = Instant::now();
+    let (mut ws, _) = tokio_tungstenite::connect_async(&options.url).await?;
+
+    let challenge = loop {
+        let packet = recv(&mut ws)
+            .await?
+            .ok_or("Closed before the auth challenge")?;
+        if packet["id"] == 1 {
+            break packet;
+        }
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(options.secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(
+        challenge["challenge"]
+            .as_str()
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    let response = hex::encode(mac.finalize().into_bytes());
+    send(
+        &mut ws,
+        json!({ "id": 4, "version": 3, "capabilities": ["commandResponse"] }),
+    )
+    .await?;
+    send(&mut ws, json!({ "id": 3, "response": response })).await?;
+    loop {
+        let packet = recv(&mut ws).await?.ok_or("Closed while authenticating")?;
+        match packet["id"].as_i64() {
+            Some(2) => break,
+            Some(-1) => return Err(format!("Refused, {}", packet["message"]).into()),
+            _ => {}
+        }
+    }
+    let mut stats = stats.lock().unwrap();
+    stats.connected += 1;
+    stats.handshakes.push(started.elapsed());
+    Ok(ws)
+}
+
+/// Register as server `n` and answer the bridge until the connection ends
+async fn simulate(
+    n: usize,
+    mut ws: Socket,
+    options: &Options,
+    stats: &Mutex<Stats>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name = format!("{}-{}", options.prefix, n);
+    send(&mut ws, json!({ "id": 0, "name": name })).await?;
+    send(
+        &mut ws,
+        json!({ "id": 1, "ctrlChannelId": options.ctrl_channel_id }),
+    )
+    .await?;
+
+    let mut malformed = tokio::time::interval(options.malformed.unwrap_or(FOREVER));
+    // The first tick is immediate, don't send anything malformed before registering
+    malformed.tick().await;
+    let mut sent_malformed = VecDeque::<Instant>::new();
+    let mut next_malformed = n;
+    loop {
+        tokio::select! {
+            packet = recv(&mut ws) => {
+                let packet = match packet? {
+                    Some(packet) => packet,
+                    None => break,
+                };
+                match packet["id"].as_i64() {
+                    Some(0) => {
+                        send(&mut ws, options.script.respond(&packet)).await?;
+                        stats.lock().unwrap().commands += 1;
+                    }
+                    Some(-1) => {
+                        let error = packet["error"].as_str().unwrap_or("Unknown").to_string();
+                        let mut stats = stats.lock().unwrap();
+                        if error.starts_with("Packet") {
+                            if let Some(sent) = sent_malformed.pop_front() {
+                                stats.error_round_trips.push(sent.elapsed());
+                            }
+                        } else {
+                            println!("{}: {} {}", name, error, packet["message"]);
+                        }
+                        *stats.errors.entry(error).or_default() += 1;
+                    }
+                    Some(7) => {
+                        println!(
+                            "{}: bridge going away, reconnect after {}s",
+                            name, packet["reconnectAfter"]
+                        );
+                        break;
+                    }
+                    _ => {}
+                }
+            },
+            _ = malformed.tick(), if options.malformed.is_some() => {
+                let packet = MALFORMED[next_malformed % MALFORMED.len()];
+                next_malformed += 1;
+                ws.send(Message::Text(packet.to_string())).await?;
+                sent_malformed.push_back(Instant::now());
+                stats.lock().unwrap().malformed += 1;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn send(ws: &mut Socket, packet: Value) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ws.send(Message::Text(packet.to_string())).await?;
+    Ok(())
+}
+
+/// The next packet, or `None` once the connection is closed
+async fn recv(ws: &mut Socket) -> Result<Option<Value>, Box<dyn Error + Send + Sync>> {
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(Some(serde_json::from_str(&text)?)),
+            Some(Ok(Message::Close(_))) | None => return Ok(None),
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(err.into()),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    let options = match Options::from_args() {
+        Ok(options) => Arc::new(options),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let started = Instant::now();
+
+    let mut servers = vec![];
+    for n in 1..=options.servers {
+        let (options, stats) = (options.clone(), stats.clone());
+        servers.push(tokio::spawn(async move {
+            match connect(&options, &stats).await {
+                Ok(ws) => {
+                    if let Err(err) = simulate(n, ws, &options, &stats).await {
+                        eprintln!("{}-{}: {}", options.prefix, n, err);
+                    }
+                    stats.lock().unwrap().disconnected += 1;
+                }
+                Err(err) => {
+                    eprintln!("{}-{}: {}", options.prefix, n, err);
+                    stats.lock().unwrap().failed += 1;
+                }
+            }
+        }));
+    }
+
+    let mut report = tokio::time::interval(options.report);
+    report.tick().await;
+    let deadline = tokio::time::sleep(options.duration.unwrap_or(FOREVER));
+    tokio::pin!(deadline);
+    let all_done = futures::future::join_all(servers);
+    tokio::pin!(all_done);
+    loop {
+        tokio::select! {
+            _ = report.tick() => stats.lock().unwrap().print(started.elapsed()),
+            _ = &mut all_done => break,
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    let stats = stats.lock().unwrap();
+    stats.print(started.elapsed());
+    if stats.connected == 0 {
+        process::exit(1);
+    }
+}
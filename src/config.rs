@@ -0,0 +1,276 @@
+//! Typed configuration, loaded from the YAML file given with `--config` (`config.yaml` by default)
+use crate::{
+    admin::AdminConfig,
+    discord::{confirmation::ConfirmationConfig, permissions::Permissions},
+    metrics::MetricsConfig,
+    ws::{
+        HeartbeatConfig, NameConflictPolicy, ResumeConfig, ServerEventType, ShutdownConfig,
+        TlsConfig,
+    },
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap, convert::TryFrom, env, fs, io, net::SocketAddr, path::PathBuf,
+    time::Duration,
+};
+use thiserror::Error;
+use twilight_model::{gateway::Intents, id::ChannelId};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Usage: tc-discord [--config <path>], unexpected argument {0}")]
+    UnknownArgument(String),
+    #[error("Usage: tc-discord [--config <path>], {0} needs a path")]
+    MissingArgument(String),
+    #[error("Could not read config file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Invalid config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: serde_yaml::Error,
+    },
+    #[error("Invalid config file {path}: {message}")]
+    Invalid { path: PathBuf, message: String },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    /// Address the plugin listener binds to
+    #[serde(default = "default_bind")]
+    pub bind: SocketAddr,
+    /// Serve plugins over TLS instead of plain TCP
+    #[serde(default = "Default::default")]
+    pub tls: Option<TlsConfig>,
+    /// Path to the log4rs configuration
+    #[serde(default = "default_log_config")]
+    pub log_config: PathBuf,
+    /// Path to the JSON lines audit log
+    #[serde(default = "default_audit_log")]
+    pub audit_log: PathBuf,
+    pub discord: DiscordConfig,
+    pub plugins: PluginConfig,
+    #[serde(default = "Default::default")]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default = "Default::default")]
+    pub resume: ResumeConfig,
+    #[serde(default = "Default::default")]
+    pub shutdown: ShutdownConfig,
+    /// What happens when a server asks for a name already taken in its control channel
+    #[serde(default = "Default::default")]
+    pub name_conflict: NameConflictPolicy,
+    /// Serve the admin HTTP API, off unless configured
+    #[serde(default = "Default::default")]
+    pub admin: Option<AdminConfig>,
+    /// Serve prometheus metrics, off unless configured
+    #[serde(default = "Default::default")]
+    pub metrics: Option<MetricsConfig>,
+    /// Rules keyed by control channel id, see [`Permissions`]
+    #[serde(default = "Default::default")]
+    pub permissions: Permissions,
+    /// Which commands from discord need a Confirm click before they are sent
+    #[serde(default = "Default::default")]
+    pub confirmation: ConfirmationConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub token: Secret,
+    #[serde(default = "default_intents")]
+    pub intents: Vec<Intent>,
+    /// Where lifecycle notifications for servers in a control channel go, keyed by control
+    /// channel id. Control channels not listed here get their own notifications
+    #[serde(default = "Default::default")]
+    pub notification_channels: HashMap<String, ChannelId>,
+    /// Which kinds of server event are posted, keyed by control channel id. Control channels not
+    /// listed here get every kind
+    #[serde(default = "Default::default")]
+    pub event_filters: HashMap<String, Vec<ServerEventType>>,
+}
+
+impl DiscordConfig {
+    pub fn intents(&self) -> Intents {
+        self.intents
+            .iter()
+            .fold(Intents::empty(), |intents, intent| intents | intent.bits())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PluginConfig {
+    /// Shared secret plugins sign the auth challenge with
+    pub secret: Secret,
+}
+
+/// A secret read from the config file, an environment variable or a file
+/// ```yaml
+/// token:
+///   env: DISCORD_TOKEN
+/// ```
+#[derive(Deserialize)]
+#[serde(try_from = "SecretSource")]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+enum SecretSource {
+    Value(String),
+    Env(String),
+    File(PathBuf),
+}
+
+impl TryFrom<SecretSource> for Secret {
+    type Error = String;
+
+    fn try_from(source: SecretSource) -> Result<Self, Self::Error> {
+        let secret = match source {
+            SecretSource::Value(value) => value,
+            SecretSource::Env(name) => {
+                env::var(&name).map_err(|_| format!("environment variable {} is not set", name))?
+            }
+            SecretSource::File(path) => fs::read_to_string(&path)
+                .map_err(|err| format!("could not read {}: {}", path.display(), err))?
+                .trim()
+                .to_string(),
+        };
+
+        if secret.is_empty() {
+            return Err("secret is empty".to_string());
+        }
+        Ok(Secret(secret))
+    }
+}
+
+/// Gateway intents, by their snake case name
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Intent {
+    Guilds,
+    GuildMembers,
+    GuildBans,
+    GuildEmojis,
+    GuildIntegrations,
+    GuildWebhooks,
+    GuildInvites,
+    GuildVoiceStates,
+    GuildPresences,
+    GuildMessages,
+    GuildMessageReactions,
+    GuildMessageTyping,
+    DirectMessages,
+    DirectMessageReactions,
+    DirectMessageTyping,
+}
+
+impl Intent {
+    fn bits(self) -> Intents {
+        match self {
+            Intent::Guilds => Intents::GUILDS,
+            Intent::GuildMembers => Intents::GUILD_MEMBERS,
+            Intent::GuildBans => Intents::GUILD_BANS,
+            Intent::GuildEmojis => Intents::GUILD_EMOJIS,
+            Intent::GuildIntegrations => Intents::GUILD_INTEGRATIONS,
+            Intent::GuildWebhooks => Intents::GUILD_WEBHOOKS,
+            Intent::GuildInvites => Intents::GUILD_INVITES,
+            Intent::GuildVoiceStates => Intents::GUILD_VOICE_STATES,
+            Intent::GuildPresences => Intents::GUILD_PRESENCES,
+            Intent::GuildMessages => Intents::GUILD_MESSAGES,
+            Intent::GuildMessageReactions => Intents::GUILD_MESSAGE_REACTIONS,
+            Intent::GuildMessageTyping => Intents::GUILD_MESSAGE_TYPING,
+            Intent::DirectMessages => Intents::DIRECT_MESSAGES,
+            Intent::DirectMessageReactions => Intents::DIRECT_MESSAGE_REACTIONS,
+            Intent::DirectMessageTyping => Intents::DIRECT_MESSAGE_TYPING,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file named on the command line
+    pub fn from_args() -> Result<Self, ConfigError> {
+        let mut path = PathBuf::from("config.yaml");
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config" => {
+                    path = args.next().ok_or(ConfigError::MissingArgument(arg))?.into()
+                }
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+
+        Self::load(path)
+    }
+
+    pub fn load(path: PathBuf) -> Result<Self, ConfigError> {
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(source) => return Err(ConfigError::Read { path, source }),
+        };
+        let config: Config = match serde_yaml::from_str(&source) {
+            Ok(config) => config,
+            Err(source) => return Err(ConfigError::Parse { path, source }),
+        };
+
+        if let Err(message) = config.validate() {
+            return Err(ConfigError::Invalid { path, message });
+        }
+        Ok(config)
+    }
+
+    /// Checks that can't be expressed in the types themselves
+    fn validate(&self) -> Result<(), String> {
+        if self.heartbeat.interval.as_secs() == 0 {
+            return Err("heartbeat.interval must be at least 1 second".to_string());
+        }
+        if self.heartbeat.missed_beats == 0 {
+            return Err("heartbeat.missedBeats must be at least 1".to_string());
+        }
+        if self.shutdown.drain_timeout.as_secs() == 0 {
+            return Err("shutdown.drainTimeout must be at least 1 second".to_string());
+        }
+        if let Some(tls) = &self.tls {
+            if tls.reload_interval.as_secs() == 0 {
+                return Err("tls.reloadInterval must be at least 1 second".to_string());
+            }
+        }
+        if self.confirmation.timeout.as_secs() == 0 {
+            return Err("confirmation.timeout must be at least 1 second".to_string());
+        }
+        if self.discord.intents.is_empty() {
+            return Err("discord.intents must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize a whole number of seconds into a `Duration`
+pub fn seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn default_bind() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+fn default_log_config() -> PathBuf {
+    PathBuf::from("log4rs.yaml")
+}
+
+fn default_audit_log() -> PathBuf {
+    PathBuf::from("audit.jsonl")
+}
+
+fn default_intents() -> Vec<Intent> {
+    vec![Intent::GuildMessages]
+}
@@ -0,0 +1,112 @@
+//! Relays chat between game servers and their bridge channels
+//!
+//! Game chat is posted through a webhook, so each player shows up under their own name and avatar
+use crate::{
+    discord::platform::{ChatPlatform, PlatformError, WebhookMessage},
+    ws::{Am, ChatEvent, WsManager},
+};
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use twilight_model::{
+    channel::Message,
+    id::{ChannelId, WebhookId},
+};
+
+/// Name of the webhook the bridge creates in, and reuses from, each bridge channel
+const WEBHOOK_NAME: &str = "tc-discord chat bridge";
+
+/// Post every chat message from `events` until the publisher goes away
+pub async fn run(platform: &dyn ChatPlatform, mut events: Receiver<ChatEvent>) {
+    let mut webhooks = HashMap::new();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {} chat messages", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let channel_id = match event
+            .bridge_channel_id
+            .parse()
+            .ok()
+            .and_then(ChannelId::new)
+        {
+            Some(channel_id) => channel_id,
+            None => {
+                debug!(
+                    "{} has an invalid bridge channel {}",
+                    event.name, event.bridge_channel_id
+                );
+                continue;
+            }
+        };
+
+        if let Err(err) = post(platform, &mut webhooks, channel_id, &event).await {
+            error!(
+                "Error relaying chat from {} to discord, {}",
+                event.name, err
+            );
+            // The webhook may have been deleted, look it up again next time
+            webhooks.remove(&channel_id);
+        }
+    }
+}
+
+async fn post(
+    platform: &dyn ChatPlatform,
+    webhooks: &mut HashMap<ChannelId, (WebhookId, String)>,
+    channel_id: ChannelId,
+    event: &ChatEvent,
+) -> Result<(), PlatformError> {
+    let (webhook_id, token) = match webhooks.get(&channel_id) {
+        Some(webhook) => webhook.clone(),
+        None => {
+            let webhook = platform.webhook(channel_id, WEBHOOK_NAME).await?;
+            webhooks.insert(channel_id, webhook.clone());
+            webhook
+        }
+    };
+
+    let message = WebhookMessage {
+        username: event.player.clone(),
+        content: event.text.clone(),
+        avatar_url: event.avatar.clone(),
+    };
+    platform.execute_webhook(webhook_id, token, message).await
+}
+
+/// Send a message posted in a bridge channel to every server bridged with it
+pub async fn relay_to_servers(ws_mgr: &Am<WsManager>, msg: &Message) {
+    // Skip our own webhook posts and other bots, so messages don't echo back and forth
+    if msg.webhook_id.is_some() || msg.author.bot || msg.content.is_empty() {
+        return;
+    }
+
+    let servers = ws_mgr
+        .lock()
+        .await
+        .get_connected_by_bridge_channel_id(&msg.channel_id.to_string());
+    if servers.is_empty() {
+        return;
+    }
+
+    let author = msg
+        .member
+        .as_ref()
+        .and_then(|member| member.nick.clone())
+        .unwrap_or_else(|| msg.author.name.clone());
+    for (info, server) in servers {
+        if let Err(err) = server
+            .lock()
+            .await
+            .send_chat_message(author.clone(), msg.content.clone())
+            .await
+        {
+            error!("Error relaying chat to {}, {}", info.name, err);
+        }
+    }
+}
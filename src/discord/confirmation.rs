@@ -0,0 +1,460 @@
+//! Commands that reach many servers, or run something matching a deny pattern, are held back until
+//! someone clicks Confirm on the message the bridge posts about them
+//!
+//! Only commands from discord are held, the admin API sends straight away
+use crate::{
+    audit::{self, AuditLog, AuditRecord, AuditServer, CommandRecord, Outcome},
+    config,
+    discord::{
+        create_embed, create_error_embed, dispatch_server_command,
+        permissions::{Invoker, Operation, Permissions},
+        platform::ChatPlatform,
+        server_command::ServerCommand,
+        server_names,
+    },
+    ws::{Am, WsManager},
+};
+use log::info;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use twilight_embed_builder::{EmbedError, EmbedFieldBuilder};
+use twilight_model::{
+    application::{
+        callback::{CallbackData, InteractionResponse},
+        component::{button::ButtonStyle, ActionRow, Button, Component},
+        interaction::MessageComponentInteraction,
+    },
+    channel::{embed::Embed, message::MessageFlags},
+    id::{ChannelId, UserId},
+};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RawConfirmationConfig {
+    #[serde(default = "Default::default")]
+    max_targets: Option<usize>,
+    #[serde(default = "Default::default")]
+    deny_pattern: Option<String>,
+    #[serde(default = "default_timeout", deserialize_with = "config::seconds")]
+    timeout: Duration,
+}
+
+/// Which commands need confirming, neither rule applies unless configured
+#[derive(Deserialize)]
+#[serde(try_from = "RawConfirmationConfig")]
+pub struct ConfirmationConfig {
+    /// Commands reaching more servers than this need confirming
+    pub max_targets: Option<usize>,
+    /// Commands running anything whose first word matches this need confirming, anchored
+    pub deny_pattern: Option<Regex>,
+    /// How long Confirm can be clicked for
+    pub timeout: Duration,
+}
+
+impl TryFrom<RawConfirmationConfig> for ConfirmationConfig {
+    type Error = regex::Error;
+
+    fn try_from(raw: RawConfirmationConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            max_targets: raw.max_targets,
+            // Anchored, so that `op` does not also hold back `stop` or `tp @a scope`
+            deny_pattern: raw
+                .deny_pattern
+                .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+                .transpose()?,
+            timeout: raw.timeout,
+        })
+    }
+}
+
+impl Default for ConfirmationConfig {
+    fn default() -> Self {
+        Self {
+            max_targets: None,
+            deny_pattern: None,
+            timeout: default_timeout(),
+        }
+    }
+}
+
+impl ConfirmationConfig {
+    /// Why `executable` needs confirming before it is sent to `targets` servers, if it does
+    pub fn reason(&self, executable: &ServerCommand, targets: usize) -> Option<String> {
+        if let Some(max_targets) = self.max_targets {
+            if targets > max_targets {
+                return Some(format!(
+                    "Reaches {} servers, more than {}",
+                    targets, max_targets
+                ));
+            }
+        }
+        let pattern = self.deny_pattern.as_ref()?;
+        executable
+            .run
+            .iter()
+            .find(|command| {
+                command
+                    .trim_start_matches('/')
+                    .split_whitespace()
+                    .next()
+                    .is_some_and(|name| pattern.is_match(name))
+            })
+            .map(|command| format!("Runs `{}`", command))
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// A command waiting for Confirm or Cancel
+struct Pending {
+    executable: ServerCommand,
+    channel_id: ChannelId,
+    requested_by: UserId,
+    /// The servers it would have gone to when it was held back
+    servers: Vec<AuditServer>,
+    expires: Instant,
+}
+
+/// A click on a Confirm or Cancel button
+pub struct Click {
+    pub custom_id: String,
+    pub channel_id: ChannelId,
+    pub invoker: Invoker,
+}
+
+/// How a click is answered
+#[derive(Debug)]
+pub enum ClickReply {
+    /// Replace the confirmation message, taking its buttons away
+    Update(Embed),
+    /// Tell only whoever clicked, leaving the buttons in place
+    Private(Embed),
+}
+
+impl Pending {
+    /// The audit log entry for what became of the command
+    fn record(&self, id: Uuid, outcome: Outcome) -> AuditRecord {
+        AuditRecord::Command(CommandRecord {
+            timestamp: audit::now(),
+            request_id: id,
+            user_id: self.requested_by.get(),
+            channel_id: self.channel_id.get(),
+            on: self.executable.on.clone(),
+            run: self.executable.run.clone(),
+            query: self.executable.query.clone(),
+            set: self.executable.set.clone(),
+            servers: self.servers.clone(),
+            outcome,
+        })
+    }
+}
+
+/// Commands held back until someone confirms them. Holding, cancelling, expiring and sending once
+/// confirmed are all recorded in the audit log under the id of the held command
+pub struct Confirmations {
+    config: ConfirmationConfig,
+    audit: Arc<AuditLog>,
+    pending: Arc<Mutex<HashMap<Uuid, Pending>>>,
+}
+
+impl Confirmations {
+    pub fn new(config: ConfirmationConfig, audit: Arc<AuditLog>) -> Self {
+        Self {
+            config,
+            audit,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Hold `executable` back if a rule says it needs confirming, returning the message asking for
+    /// confirmation. Commands that would not be sent anyway are never held, so that their error is
+    /// reported and recorded as usual
+    pub(crate) async fn hold(
+        &self,
+        ws_mgr: &Am<WsManager>,
+        permissions: &Permissions,
+        invoker: &Invoker,
+        executable: &ServerCommand,
+        channel_id: ChannelId,
+    ) -> Result<Option<(Embed, Vec<Component>)>, EmbedError> {
+        if executable.dry_run
+            || (self.config.max_targets.is_none() && self.config.deny_pattern.is_none())
+        {
+            return Ok(None);
+        }
+
+        let preview = ServerCommand {
+            dry_run: true,
+            ..executable.clone()
+        };
+        let servers = match dispatch_server_command(
+            ws_mgr,
+            permissions,
+            &self.audit,
+            Some(invoker),
+            &preview,
+            channel_id,
+            None,
+        )
+        .await
+        {
+            Ok(dispatched) => dispatched.servers,
+            Err(_) => return Ok(None),
+        };
+        let reason = match self.config.reason(executable, servers.len()) {
+            Some(reason) => reason,
+            None => return Ok(None),
+        };
+
+        let id = Uuid::new_v4();
+        info!(
+            "Holding {} from {} for confirmation, {}",
+            id, invoker.user_id, reason
+        );
+        let embed = create_embed(
+            "Confirmation needed",
+            None,
+            vec![
+                EmbedFieldBuilder::new("Why", reason.clone()).build(),
+                EmbedFieldBuilder::new(
+                    format!("Would be sent to {} servers", servers.len()),
+                    server_names(&servers),
+                )
+                .build(),
+                EmbedFieldBuilder::new(
+                    "Expires",
+                    format!("<t:{}:R>", audit::now() + self.config.timeout.as_secs()),
+                )
+                .build(),
+            ],
+        )?;
+
+        let pending = Pending {
+            executable: executable.clone(),
+            channel_id,
+            requested_by: invoker.user_id,
+            servers,
+            expires: Instant::now() + self.config.timeout,
+        };
+        self.audit
+            .record(pending.record(id, Outcome::Held { reason }))
+            .await;
+        self.pending.lock().unwrap().insert(id, pending);
+
+        // Left unclicked, the command is dropped once the timeout passes
+        let (timeout, expiring, audit) = (
+            self.config.timeout,
+            self.pending.clone(),
+            self.audit.clone(),
+        );
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let expired = expiring.lock().unwrap().remove(&id);
+            if let Some(expired) = expired {
+                info!("Nobody confirmed {} in time", id);
+                audit.record(expired.record(id, Outcome::Expired)).await;
+            }
+        });
+        Ok(Some((embed, buttons(id))))
+    }
+
+    /// Send or drop the command a button belongs to. Whoever held it back may click, as may anyone
+    /// allowed to send it to every server it would have gone to
+    pub async fn click(
+        &self,
+        ws_mgr: &Am<WsManager>,
+        permissions: &Permissions,
+        click: &Click,
+    ) -> Result<ClickReply, EmbedError> {
+        let (confirmed, id) = match click.custom_id.split_once(':') {
+            Some((action, id)) if action == "confirm" || action == "cancel" => {
+                (action == "confirm", Uuid::parse_str(id).ok())
+            }
+            _ => (false, None),
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                return create_error_embed("Unknown button", &click.custom_id)
+                    .map(ClickReply::Private)
+            }
+        };
+
+        let pending = self.pending.lock().unwrap().remove(&id);
+        let pending = match pending {
+            // Buttons only ever come with the message they were posted with
+            Some(pending) if pending.channel_id != click.channel_id => {
+                self.pending.lock().unwrap().insert(id, pending);
+                return create_error_embed("Unknown button", &click.custom_id)
+                    .map(ClickReply::Private);
+            }
+            // Clicked before the expiry task got to it
+            Some(pending) if pending.expires <= Instant::now() => {
+                self.audit
+                    .record(pending.record(id, Outcome::Expired))
+                    .await;
+                None
+            }
+            pending => pending,
+        };
+        let pending = match pending {
+            Some(pending) => pending,
+            None => {
+                return create_error_embed(
+                    "Confirmation expired",
+                    "This command was already confirmed, cancelled or timed out",
+                )
+                .map(ClickReply::Update)
+            }
+        };
+
+        let operations = Operation::of(&pending.executable);
+        let allowed = click.invoker.user_id == pending.requested_by
+            || pending.servers.iter().all(|server| {
+                operations.iter().all(|operation| {
+                    permissions.allows(pending.channel_id, &click.invoker, &server.name, *operation)
+                })
+            });
+        if !allowed {
+            self.pending.lock().unwrap().insert(id, pending);
+            return create_error_embed(
+                "Permission denied",
+                "You are not allowed to confirm or cancel this command",
+            )
+            .map(ClickReply::Private);
+        }
+
+        if !confirmed {
+            info!("{} cancelled {}", click.invoker.user_id, id);
+            let cancelled = Outcome::Cancelled {
+                by: click.invoker.user_id.get(),
+            };
+            self.audit.record(pending.record(id, cancelled)).await;
+            return create_embed(
+                "Command cancelled",
+                None,
+                vec![EmbedFieldBuilder::new(
+                    "Cancelled by",
+                    format!("<@{}>", click.invoker.user_id),
+                )
+                .build()],
+            )
+            .map(ClickReply::Update);
+        }
+
+        info!("{} confirmed {}", click.invoker.user_id, id);
+        // Only to the servers that were shown when it was held back
+        match dispatch_server_command(
+            ws_mgr,
+            permissions,
+            &self.audit,
+            Some(&click.invoker),
+            &pending.executable,
+            pending.channel_id,
+            Some((id, &pending.servers)),
+        )
+        .await
+        {
+            Ok(dispatched) => {
+                let mut fields = vec![EmbedFieldBuilder::new(
+                    "Confirmed by",
+                    format!("<@{}>", click.invoker.user_id),
+                )
+                .build()];
+                fields.extend(dispatched.fields());
+                create_embed("Command confirmed", None, fields)
+            }
+            Err(err) => err.embed(&pending.executable),
+        }
+        .map(ClickReply::Update)
+    }
+}
+
+/// Confirm and Cancel, with the id of the held command in their custom ids
+fn buttons(id: Uuid) -> Vec<Component> {
+    let button = |action: &str, label: &str, style| {
+        Component::Button(Button {
+            custom_id: Some(format!("{}:{}", action, id)),
+            disabled: false,
+            emoji: None,
+            label: Some(label.to_string()),
+            style,
+            url: None,
+        })
+    };
+    vec![Component::ActionRow(ActionRow {
+        components: vec![
+            button("confirm", "Confirm", ButtonStyle::Danger),
+            button("cancel", "Cancel", ButtonStyle::Secondary),
+        ],
+    })]
+}
+
+pub async fn handle_interaction(
+    component: &MessageComponentInteraction,
+    platform: &dyn ChatPlatform,
+    ws_mgr: &Am<WsManager>,
+    permissions: &Permissions,
+    confirmations: &Confirmations,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let user = component
+        .member
+        .as_ref()
+        .and_then(|member| member.user.as_ref())
+        .or(component.user.as_ref());
+    let reply = match user {
+        Some(user) => {
+            let click = Click {
+                custom_id: component.data.custom_id.clone(),
+                channel_id: component.channel_id,
+                invoker: Invoker {
+                    user_id: user.id,
+                    roles: component
+                        .member
+                        .as_ref()
+                        .map(|member| member.roles.clone())
+                        .unwrap_or_default(),
+                },
+            };
+            confirmations.click(ws_mgr, permissions, &click).await?
+        }
+        None => ClickReply::Private(create_error_embed(
+            "Permission denied",
+            "Could not identify who you are",
+        )?),
+    };
+
+    let response = match reply {
+        ClickReply::Update(embed) => InteractionResponse::UpdateMessage(CallbackData {
+            allowed_mentions: None,
+            components: Some(vec![]),
+            content: None,
+            embeds: vec![embed],
+            flags: None,
+            tts: None,
+        }),
+        ClickReply::Private(embed) => InteractionResponse::ChannelMessageWithSource(CallbackData {
+            allowed_mentions: None,
+            components: None,
+            content: None,
+            embeds: vec![embed],
+            flags: Some(MessageFlags::EPHEMERAL),
+            tts: None,
+        }),
+    };
+    platform
+        .respond(component.id, component.token.clone(), response)
+        .await?;
+
+    Ok(())
+}
@@ -1,13 +1,34 @@
+pub mod chat_bridge;
+pub mod confirmation;
+pub mod notifications;
+pub mod permissions;
+pub mod platform;
 pub mod server_command;
+pub mod server_events;
+pub mod slash_command;
 
 use crate::{
-    discord::server_command::ServerCommand,
-    ws::{Am, WsManager},
+    audit::{self, AuditLog, AuditRecord, AuditServer, CommandRecord, Outcome},
+    config::DiscordConfig,
+    discord::{
+        confirmation::{ConfirmationConfig, Confirmations},
+        permissions::{Invoker, Operation, Permissions},
+        platform::ChatPlatform,
+        server_command::ServerCommand,
+    },
+    metrics::Metrics,
+    ws::{Am, Selector, WsManager},
 };
 use futures::stream::StreamExt;
 use log::{debug, error, info};
-use regex::Regex;
-use std::{env, error::Error, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use twilight_cache_inmemory::{InMemoryCache, ResourceType};
 use twilight_embed_builder::{EmbedAuthorBuilder, EmbedBuilder, EmbedError, EmbedFieldBuilder};
 use twilight_gateway::{
@@ -16,19 +37,30 @@ use twilight_gateway::{
 };
 use twilight_http::Client as HttpClient;
 use twilight_model::{
+    application::interaction::Interaction,
     channel::embed::{Embed, EmbedField},
-    gateway::Intents,
+    id::{ChannelId, MessageId},
 };
+use uuid::Uuid;
 
-pub async fn main(ws_mgr: Am<WsManager>) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let token = env::var("DISCORD_TOKEN")?;
+pub async fn main(
+    ws_mgr: Am<WsManager>,
+    audit: Arc<AuditLog>,
+    config: DiscordConfig,
+    permissions: Permissions,
+    confirmation: ConfirmationConfig,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let token = config.token.expose().to_string();
+    let permissions = Arc::new(permissions);
+    let confirmations = Arc::new(Confirmations::new(confirmation, Arc::clone(&audit)));
 
     // This is the default scheme. It will automatically create as many
     // shards as is suggested by Discord.
     let scheme = ShardScheme::Auto;
 
-    // Use intents to only receive guild message events.
-    let (cluster, mut events) = Cluster::builder(token.to_owned(), Intents::GUILD_MESSAGES)
+    // Only receive the events the config asks for, guild messages by default.
+    let (cluster, mut events) = Cluster::builder(token.to_owned(), config.intents())
         .shard_scheme(scheme)
         .build()
         .await?;
@@ -42,13 +74,70 @@ pub async fn main(ws_mgr: Am<WsManager>) -> Result<(), Box<dyn Error + Send + Sy
         cluster_spawn.up().await;
     });
 
+    // HTTP is separate from the gateway, so create a new client.
+    let http = Arc::new(HttpClient::new(token));
+
+    // Taking the cluster down ends the event loop below, once the servers have been let go
+    let shutdown_http = Arc::clone(&http);
+    let shutdown_mgr = ws_mgr.clone();
+    let shutdown_channels = config.notification_channels.clone();
+    let signalled = Arc::new(AtomicBool::new(false));
+    let signalled2 = Arc::clone(&signalled);
+    let shutdown = tokio::spawn(async move {
+        let signal = shutdown_signal().await;
+        info!("Received {}, shutting down", signal);
+        signalled2.store(true, Ordering::SeqCst);
+
+        let mut servers = BTreeMap::new();
+        for (info, _) in shutdown_mgr.lock().await.get_connected() {
+            if !info.ctrl_channel_id.is_empty() {
+                *servers.entry(info.ctrl_channel_id).or_default() += 1;
+            }
+        }
+        // Taken out of the lock first, draining can take until the drain timeout
+        let drain = shutdown_mgr.lock().await.shutdown();
+        let drained = drain.await;
+        notifications::going_down(&*shutdown_http, servers, &shutdown_channels).await;
+
+        cluster_spawn2.down();
+        drained
+    });
+
+    // Slash commands are tied to the application, so it has to be known before registering them
+    let application_id = http
+        .current_user_application()
+        .exec()
+        .await?
+        .model()
+        .await?
+        .id;
+    http.set_application_id(application_id);
+    http.register_commands(slash_command::commands()).await?;
+
+    let lifecycle_events = ws_mgr.lock().await.subscribe();
+    let notification_http = Arc::clone(&http);
+    let notification_channels = config.notification_channels.clone();
     tokio::spawn(async move {
-        tokio::signal::ctrl_c().await.unwrap();
-        cluster_spawn2.down()
+        notifications::run(&*notification_http, lifecycle_events, notification_channels).await;
     });
 
-    // HTTP is separate from the gateway, so create a new client.
-    let http = Arc::new(HttpClient::new(token));
+    let server_events = ws_mgr.lock().await.subscribe_server_events();
+    let server_event_http = Arc::clone(&http);
+    tokio::spawn(async move {
+        server_events::run(
+            &*server_event_http,
+            server_events,
+            config.notification_channels,
+            config.event_filters,
+        )
+        .await;
+    });
+
+    let chat_events = ws_mgr.lock().await.subscribe_chat();
+    let chat_http = Arc::clone(&http);
+    tokio::spawn(async move {
+        chat_bridge::run(&*chat_http, chat_events).await;
+    });
 
     // Since we only care about new messages, make the cache only
     // cache new messages.
@@ -60,15 +149,86 @@ pub async fn main(ws_mgr: Am<WsManager>) -> Result<(), Box<dyn Error + Send + Sy
     while let Some((shard_id, event)) = events.next().await {
         // Update the cache with the event.
         cache.update(&event);
+        metrics.discord_event(shard_id);
 
         let mgr2 = ws_mgr.clone();
 
-        tokio::spawn(handle_event(shard_id, event, Arc::clone(&http), mgr2));
+        tokio::spawn(handle_event(
+            shard_id,
+            event,
+            Arc::clone(&http),
+            mgr2,
+            Arc::clone(&permissions),
+            Arc::clone(&audit),
+            Arc::clone(&confirmations),
+        ));
     }
 
+    // The event loop also ends when the gateway fails, no signal is coming then
+    if !signalled.load(Ordering::SeqCst) {
+        shutdown.abort();
+        return Err("Lost the connection to discord".into());
+    }
+    if !shutdown.await? {
+        return Err("Not every server connection closed in time".into());
+    }
     Ok(())
 }
 
+/// Wait for ctrl-c, or SIGTERM when running in a container
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "ctrl-c",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+        "ctrl-c"
+    }
+}
+
+/// Discord rejects embed fields with values longer than this
+const MAX_FIELD_VALUE_LENGTH: usize = 1024;
+
+/// Discord does not accept empty or overly long field values
+pub(crate) fn field_value(value: String) -> String {
+    if value.is_empty() {
+        "*No output*".to_string()
+    } else if value.chars().count() > MAX_FIELD_VALUE_LENGTH {
+        let mut value = value
+            .chars()
+            .take(MAX_FIELD_VALUE_LENGTH - 1)
+            .collect::<String>();
+        value.push('…');
+        value
+    } else {
+        value
+    }
+}
+
+/// The names of `servers` for an embed field, saying how many were left out if they don't all fit
+pub(crate) fn server_names(servers: &[AuditServer]) -> String {
+    // Room for the longest possible " and N more"
+    let limit = MAX_FIELD_VALUE_LENGTH - format!(" and {} more", servers.len()).len();
+    let mut names = String::new();
+    for (shown, server) in servers.iter().enumerate() {
+        let separator = if shown == 0 { "" } else { ", " };
+        if names.chars().count() + separator.len() + server.name.chars().count() > limit {
+            names.push_str(&format!(" and {} more", servers.len() - shown));
+            return field_value(names.trim_start().to_string());
+        }
+        names.push_str(separator);
+        names.push_str(&server.name);
+    }
+    field_value(names)
+}
+
 pub fn create_error_embed(title: &str, error: &str) -> Result<Embed, EmbedError> {
     EmbedBuilder::new()
         .color(0xda2b46)
@@ -96,126 +256,394 @@ pub fn create_embed(
     })
 }
 
+/// Why a server command was not sent
+pub(crate) enum DispatchError {
+    /// The `on` selector didn't parse
+    InvalidSelector(String),
+    NoServers,
+    PermissionDenied(Vec<String>),
+    /// The servers matched differ from the ones that were confirmed
+    TargetsChanged,
+}
+
+impl DispatchError {
+    pub(crate) fn embed(&self, executable: &ServerCommand) -> Result<Embed, EmbedError> {
+        match self {
+            DispatchError::InvalidSelector(err) => create_error_embed("Invalid selector", err),
+            DispatchError::NoServers => create_error_embed(
+                "Could not find any servers",
+                &format!("No servers matched the query {}", &executable.on),
+            ),
+            DispatchError::PermissionDenied(servers) => create_error_embed(
+                "Permission denied",
+                &format!(
+                    "You are not allowed to send this command to {}",
+                    servers.join(", ")
+                ),
+            ),
+            DispatchError::TargetsChanged => create_error_embed(
+                "Targets changed",
+                &format!(
+                    "The servers matching {} changed since the command was held back, please send it again",
+                    &executable.on
+                ),
+            ),
+        }
+    }
+}
+
+/// The servers a command was meant for, and those of them it couldn't be sent to
+pub(crate) struct Dispatched {
+    pub servers: Vec<AuditServer>,
+    /// Servers whose connection was already gone
+    pub failed: Vec<AuditServer>,
+}
+
+impl Dispatched {
+    /// The servers the command was sent to
+    pub fn sent(&self) -> Vec<AuditServer> {
+        self.servers
+            .iter()
+            .filter(|server| !self.failed.iter().any(|failed| failed.uuid == server.uuid))
+            .cloned()
+            .collect()
+    }
+
+    /// Embed fields saying how many servers the command went to, and which it didn't reach
+    pub fn fields(&self) -> Vec<EmbedField> {
+        let mut fields =
+            vec![EmbedFieldBuilder::new("Servers", self.sent().len().to_string()).build()];
+        if !self.failed.is_empty() {
+            fields.push(
+                EmbedFieldBuilder::new("Could not reach", server_names(&self.failed)).build(),
+            );
+        }
+        fields
+    }
+}
+
+/// Send `executable` to every server in `channel_id` matched by its `on` selector, returning the
+/// servers it was meant for and those it couldn't be sent to. Nothing is sent unless `invoker` may
+/// send it to every server, no invoker means the admin API, which may send anything.
+/// Every attempt is recorded in the audit log, whether or not anything was sent. Dry runs return
+/// the servers that would have been sent to, and are neither sent nor recorded.
+/// Confirmed commands keep the request id they were held under, and are only sent if they still
+/// match exactly the `confirmed` servers.
+pub(crate) async fn dispatch_server_command(
+    ws_mgr: &Am<WsManager>,
+    permissions: &Permissions,
+    audit: &AuditLog,
+    invoker: Option<&Invoker>,
+    executable: &ServerCommand,
+    channel_id: ChannelId,
+    confirmed: Option<(Uuid, &[AuditServer])>,
+) -> Result<Dispatched, DispatchError> {
+    let selector = Selector::parse(&executable.on);
+    let server_selector = match &selector {
+        Ok(selector) => ws_mgr
+            .lock()
+            .await
+            .get_connections_by_selector(selector, &channel_id.to_string()),
+        Err(_) => vec![],
+    };
+
+    info!("{}", server_selector.len());
+
+    let servers = server_selector
+        .iter()
+        .map(|(info, _)| AuditServer {
+            uuid: info.uuid,
+            name: info.name.clone(),
+        })
+        .collect::<Vec<AuditServer>>();
+
+    // One id for every server, each server replies with its own embed
+    let request_id = confirmed.map_or_else(Uuid::new_v4, |(request_id, _)| request_id);
+    let result = async {
+        if let Err(err) = &selector {
+            return Err(DispatchError::InvalidSelector(err.to_string()));
+        }
+        if server_selector.is_empty() {
+            debug!("No servers found");
+            return Err(DispatchError::NoServers);
+        }
+        if let Some((_, confirmed)) = confirmed {
+            let uuids = |servers: &[AuditServer]| {
+                servers
+                    .iter()
+                    .map(|server| server.uuid)
+                    .collect::<HashSet<Uuid>>()
+            };
+            if uuids(&servers) != uuids(confirmed) {
+                info!(
+                    "Servers matching {} changed since confirming",
+                    executable.on
+                );
+                return Err(DispatchError::TargetsChanged);
+            }
+        }
+
+        if let Some(invoker) = invoker {
+            let operations = Operation::of(executable);
+            let denied = servers
+                .iter()
+                .filter(|server| {
+                    !operations.iter().all(|operation| {
+                        permissions.allows(channel_id, invoker, &server.name, *operation)
+                    })
+                })
+                .map(|server| server.name.clone())
+                .collect::<Vec<String>>();
+            if !denied.is_empty() {
+                info!("Denied {} access to {}", invoker.user_id, denied.join(", "));
+                return Err(DispatchError::PermissionDenied(denied));
+            }
+        }
+        if executable.dry_run {
+            return Ok(vec![]);
+        }
+
+        let mut failed = vec![];
+        for (server, audit_server) in server_selector.iter().zip(&servers) {
+            debug!("Sending {} to {}", request_id, server.0.uuid);
+            if let Err(err) = server
+                .1
+                .lock()
+                .await
+                .send_server_command(request_id, channel_id, executable.clone())
+                .await
+            {
+                error!("Error sending packet to {}, {}", server.0.uuid, err);
+                failed.push(audit_server.clone());
+            }
+        }
+        Ok(failed)
+    }
+    .await;
+
+    if executable.dry_run {
+        return result.map(|failed| Dispatched { servers, failed });
+    }
+
+    let outcome = match &result {
+        Ok(failed) => Outcome::Sent {
+            failed: failed.iter().map(|server| server.name.clone()).collect(),
+        },
+        Err(DispatchError::InvalidSelector(error)) => Outcome::InvalidSelector {
+            error: error.clone(),
+        },
+        Err(DispatchError::NoServers) => Outcome::NoServers,
+        Err(DispatchError::PermissionDenied(denied)) => Outcome::PermissionDenied {
+            denied: denied.clone(),
+        },
+        Err(DispatchError::TargetsChanged) => Outcome::TargetsChanged,
+    };
+    audit
+        .record(AuditRecord::Command(CommandRecord {
+            timestamp: audit::now(),
+            request_id,
+            user_id: invoker.map(|invoker| invoker.user_id.get()).unwrap_or(0),
+            channel_id: channel_id.get(),
+            on: executable.on.clone(),
+            run: executable.run.clone(),
+            query: executable.query.clone(),
+            set: executable.set.clone(),
+            servers: servers.clone(),
+            outcome,
+        }))
+        .await;
+
+    result.map(|failed| Dispatched { servers, failed })
+}
+
+/// Reply to a dry run, listing the servers that would have been sent to
+pub(crate) fn dry_run_embed(servers: &[AuditServer]) -> Result<Embed, EmbedError> {
+    create_embed(
+        "Dry run",
+        None,
+        vec![EmbedFieldBuilder::new(
+            format!("Would be sent to {} servers", servers.len()),
+            server_names(servers),
+        )
+        .build()],
+    )
+}
+
+/// Build an embed listing every server controlled from `channel_id`
+async fn list_servers_embed(
+    ws_mgr: &Am<WsManager>,
+    channel_id: ChannelId,
+) -> Result<Embed, EmbedError> {
+    let fields = ws_mgr
+        .lock()
+        .await
+        .get_connected_by_ctrl_channel_id(&channel_id.to_string())
+        .into_iter()
+        .map(|(info, _)| {
+            let mut value = info.uuid.to_string();
+            if !info.tags.is_empty() {
+                let tags = info
+                    .tags
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<String>>();
+                value = format!("{}\n`{}`", value, tags.join("`, `"));
+            }
+            EmbedFieldBuilder::new(info.name, value).build()
+        })
+        .collect();
+
+    Ok(Embed {
+        fields,
+        ..EmbedBuilder::new().title("Active Server").build()?
+    })
+}
+
+/// A server command posted as a YAML code block
+pub struct CommandMessage {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub invoker: Invoker,
+    pub content: String,
+}
+
+/// Parse and send a server command, replying to the message if it couldn't be sent or needs
+/// confirming first
+pub async fn handle_command_message(
+    platform: &dyn ChatPlatform,
+    ws_mgr: &Am<WsManager>,
+    permissions: &Permissions,
+    audit: &AuditLog,
+    confirmations: &Confirmations,
+    message: &CommandMessage,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let command = message
+        .content
+        .strip_prefix("```yaml\n")
+        .and_then(|command| command.strip_suffix("```"));
+    let parsed = match command {
+        Some(command) => serde_yaml::from_str::<ServerCommand>(command).map_err(|e| e.to_string()),
+        None => Err("Commands go in a ```yaml code block".to_string()),
+    };
+    let executable = match parsed {
+        Ok(executable) => executable,
+        Err(err) => {
+            let embed = create_error_embed("Error parsing command", &err)?;
+            return platform
+                .reply(message.channel_id, message.message_id, embed)
+                .await;
+        }
+    };
+
+    if let Some((embed, components)) = confirmations
+        .hold(
+            ws_mgr,
+            permissions,
+            &message.invoker,
+            &executable,
+            message.channel_id,
+        )
+        .await?
+    {
+        return platform
+            .reply_with_components(message.channel_id, message.message_id, embed, components)
+            .await;
+    }
+
+    let reply = match dispatch_server_command(
+        ws_mgr,
+        permissions,
+        audit,
+        Some(&message.invoker),
+        &executable,
+        message.channel_id,
+        None,
+    )
+    .await
+    {
+        Ok(dispatched) if executable.dry_run => dry_run_embed(&dispatched.servers)?,
+        Ok(dispatched) if dispatched.failed.is_empty() => return Ok(()),
+        Ok(dispatched) => create_error_embed(
+            "Could not reach every server",
+            &format!(
+                "The command was not sent to {}",
+                server_names(&dispatched.failed)
+            ),
+        )?,
+        Err(err) => err.embed(&executable)?,
+    };
+    platform
+        .reply(message.channel_id, message.message_id, reply)
+        .await?;
+    Ok(())
+}
+
 async fn handle_event(
     shard_id: u64,
     event: Event,
     http: Arc<HttpClient>,
     ws_mgr: Am<WsManager>,
+    permissions: Arc<Permissions>,
+    audit: Arc<AuditLog>,
+    confirmations: Arc<Confirmations>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     match event {
         // Server control commands
         Event::MessageCreate(msg) if msg.content.starts_with("```yaml") => {
             info!("Got server command");
 
-            let command = msg
-                .content
-                .strip_prefix("```yaml\n")
-                .unwrap()
-                .strip_suffix("```")
-                .unwrap();
-            let executable: ServerCommand = match serde_yaml::from_str(command) {
-                Ok(a) => a,
-                Err(e) => {
-                    http.create_message(msg.channel_id)
-                        .embeds(&[create_error_embed(
-                            "Error parsing command",
-                            &format!("{}", e),
-                        )?])?
-                        .exec()
-                        .await?;
-                    return Ok(());
-                }
-            };
-
-            let server_selector = match Regex::new(&executable.on) {
-                Ok(server_selector) => {
-                    ws_mgr
-                        .lock()
-                        .await
-                        .get_connections_by_regex(
-                            server_selector,
-                            msg.channel_id.to_string(),
-                        )
-                        .await
-                }
-                Err(_) => {
-                    match ws_mgr
-                        .lock()
-                        .await
-                        .get_connection_by_name(
-                            executable.on.clone(),
-                            msg.channel_id.to_string(),
-                        )
-                        .await
-                    {
-                        Some(x) => vec![x],
-                        None => vec![],
-                    }
-                }
+            let message = CommandMessage {
+                channel_id: msg.channel_id,
+                message_id: msg.id,
+                invoker: Invoker {
+                    user_id: msg.author.id,
+                    roles: msg
+                        .member
+                        .as_ref()
+                        .map(|member| member.roles.clone())
+                        .unwrap_or_default(),
+                },
+                content: msg.content.clone(),
             };
-
-            info!("{}", server_selector.len());
-
-            if server_selector.is_empty() {
-                debug!("No servers found");
-                http.create_message(msg.channel_id)
-                    .embeds(&[create_error_embed(
-                        "Could not find any servers",
-                        &format!("No servers matched the query {}", &executable.on),
-                    )?])
-                    .unwrap()
-                    .exec()
-                    .await?;
-                return Ok(());
-            }
-
-            for server in server_selector {
-                debug!("Sending to {}", server.0);
-                match server
-                    .1
-                    .lock()
-                    .await
-                    .send_server_command(executable.clone())
-                    .await {
-                    Err(err) => {
-                        error!("Error sending packet to {}, {}", server.0, err.to_string())
-                    }
-                    _ => {}
-                };
-            }
+            handle_command_message(
+                &*http,
+                &ws_mgr,
+                &permissions,
+                &audit,
+                &confirmations,
+                &message,
+            )
+            .await?;
         }
-        // Global command (does not affect 1 server)
-        Event::MessageCreate(msg) if msg.content.starts_with("/") => {
-            let command = msg
-                .content
-                .strip_prefix("/")
-                .unwrap()
-                .split(" ")
-                .next()
-                .unwrap_or(msg.content.strip_prefix("/").unwrap());
-            if command == "list" {
-                let mut fields = vec![];
-                for (uuid, k) in ws_mgr
-                    .lock()
-                    .await
-                    .get_connected_by_ctrl_channel_id(msg.channel_id.to_string())
-                    .await
-                {
-                    fields.push(
-                        EmbedFieldBuilder::new(k.lock().await.get_name(), uuid.to_string()).build(),
-                    );
-                }
-                http.create_message(msg.channel_id)
-                    .embeds(&[Embed {
-                        fields,
-                        ..EmbedBuilder::new().title("Active Server").build().unwrap()
-                    }])
-                    .unwrap()
-                    .exec()
-                    .await
-                    .unwrap();
-            }
+        // Chat in bridge channels
+        Event::MessageCreate(msg) => {
+            chat_bridge::relay_to_servers(&ws_mgr, &msg).await;
         }
+        Event::InteractionCreate(interaction) => match &interaction.0 {
+            Interaction::ApplicationCommand(command) => {
+                slash_command::handle_interaction(
+                    command,
+                    &*http,
+                    ws_mgr,
+                    &permissions,
+                    &audit,
+                    &confirmations,
+                )
+                .await?;
+            }
+            // Confirm and Cancel buttons
+            Interaction::MessageComponent(component) => {
+                confirmation::handle_interaction(
+                    component,
+                    &*http,
+                    &ws_mgr,
+                    &permissions,
+                    &confirmations,
+                )
+                .await?;
+            }
+            _ => {}
+        },
 
         Event::ShardConnected(_) => {
             info!(
@@ -230,7 +658,7 @@ async fn handle_event(
                     .unwrap()
                     .name
             );
-            ws_mgr.lock().await.set_get_http(http.clone()).await;
+            ws_mgr.lock().await.set_platform(http.clone()).await;
         }
         _ => {}
     }
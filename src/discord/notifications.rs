@@ -0,0 +1,125 @@
+//! Posts server lifecycle events to discord
+use crate::{
+    discord::{create_embed, platform::ChatPlatform},
+    ws::{LifecycleEvent, LifecycleEventKind},
+};
+use log::{error, warn};
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use twilight_embed_builder::{EmbedError, EmbedFieldBuilder};
+use twilight_model::{channel::embed::Embed, id::ChannelId};
+
+/// Post every event from `events` until the publisher goes away
+pub async fn run(
+    platform: &dyn ChatPlatform,
+    mut events: Receiver<LifecycleEvent>,
+    notification_channels: HashMap<String, ChannelId>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {} lifecycle notifications", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let embed = match event_embed(&event) {
+            Ok(embed) => embed,
+            Err(err) => {
+                error!("Error building notification for {}, {}", event.name, err);
+                continue;
+            }
+        };
+
+        let mut channels = vec![&event.ctrl_channel_id];
+        if let LifecycleEventKind::ControlChannelChanged {
+            old_ctrl_channel_id,
+        } = &event.kind
+        {
+            channels.push(old_ctrl_channel_id);
+        }
+
+        for ctrl_channel_id in channels {
+            if let Some(channel_id) = notification_channel(&notification_channels, ctrl_channel_id)
+            {
+                post(platform, channel_id, embed.clone(), &event.name).await;
+            }
+        }
+    }
+}
+
+/// Let every control channel that had servers connected know the bridge is going down,
+/// `servers` holds how many per control channel
+pub async fn going_down(
+    platform: &dyn ChatPlatform,
+    servers: BTreeMap<String, usize>,
+    notification_channels: &HashMap<String, ChannelId>,
+) {
+    for (ctrl_channel_id, count) in servers {
+        let channel_id = match notification_channel(notification_channels, &ctrl_channel_id) {
+            Some(channel_id) => channel_id,
+            None => continue,
+        };
+        match create_embed(
+            "Bridge going down",
+            None,
+            vec![EmbedFieldBuilder::new("Servers disconnected", count.to_string()).build()],
+        ) {
+            Ok(embed) => post(platform, channel_id, embed, "the bridge").await,
+            Err(err) => error!("Error building shutdown notification, {}", err),
+        }
+    }
+}
+
+/// Where notifications for servers controlled from `ctrl_channel_id` are posted
+pub(super) fn notification_channel(
+    notification_channels: &HashMap<String, ChannelId>,
+    ctrl_channel_id: &str,
+) -> Option<ChannelId> {
+    match notification_channels.get(ctrl_channel_id) {
+        Some(channel_id) => Some(*channel_id),
+        None => ctrl_channel_id.parse().ok().and_then(ChannelId::new),
+    }
+}
+
+fn event_embed(event: &LifecycleEvent) -> Result<Embed, EmbedError> {
+    match &event.kind {
+        LifecycleEventKind::Online => create_embed("Server online", Some(&event.name), vec![]),
+        LifecycleEventKind::Offline { reason } => create_embed(
+            "Server offline",
+            Some(&event.name),
+            vec![EmbedFieldBuilder::new("Reason", reason).build()],
+        ),
+        LifecycleEventKind::Renamed { old_name } => create_embed(
+            "Server renamed",
+            Some(&event.name),
+            vec![EmbedFieldBuilder::new("Previous name", old_name).build()],
+        ),
+        LifecycleEventKind::ControlChannelChanged {
+            old_ctrl_channel_id,
+        } => create_embed(
+            "Control channel changed",
+            Some(&event.name),
+            vec![
+                EmbedFieldBuilder::new("From", format!("<#{}>", old_ctrl_channel_id)).build(),
+                EmbedFieldBuilder::new("To", format!("<#{}>", event.ctrl_channel_id)).build(),
+            ],
+        ),
+    }
+}
+
+pub(super) async fn post(
+    platform: &dyn ChatPlatform,
+    channel_id: ChannelId,
+    embed: Embed,
+    name: &str,
+) {
+    if let Err(err) = platform.send_embed(channel_id, embed).await {
+        error!(
+            "Error sending notification for {} to discord, {}",
+            name, err
+        );
+    }
+}
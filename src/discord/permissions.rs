@@ -0,0 +1,144 @@
+//! Who is allowed to send which commands to which servers
+//!
+//! Rules are read from the `permissions` section of the config file, keyed by control channel id.
+//! Anything not explicitly allowed is denied
+//! ```yaml
+//! "632402187112153090":
+//!   - roles: [632402187112153091]
+//!     servers: ".*"
+//!     operations: [run, query, set]
+//!   - users: [632402187112153092]
+//!     servers: "lobby-.*"
+//!     operations: [query, audit]
+//! ```
+//! `audit` lets the user read the audit log for the channel, and ignores `servers`. Rules without
+//! `servers` match no servers, so audit-only rules can leave it out
+use crate::discord::server_command::ServerCommand;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, convert::TryFrom};
+use twilight_model::id::{ChannelId, RoleId, UserId};
+
+/// The kinds of thing a `ServerCommand` can ask a server to do
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Run,
+    Query,
+    Set,
+    Audit,
+}
+
+impl Operation {
+    /// Every operation `command` performs
+    pub fn of(command: &ServerCommand) -> Vec<Operation> {
+        let mut operations = vec![];
+        if !command.run.is_empty() {
+            operations.push(Operation::Run);
+        }
+        if !command.query.is_empty() {
+            operations.push(Operation::Query);
+        }
+        if !command.set.is_empty() {
+            operations.push(Operation::Set);
+        }
+        operations
+    }
+}
+
+/// The discord user a command came from
+pub struct Invoker {
+    pub user_id: UserId,
+    pub roles: Vec<RoleId>,
+}
+
+#[derive(Deserialize)]
+struct RawRule {
+    #[serde(default = "Default::default")]
+    users: Vec<u64>,
+    #[serde(default = "Default::default")]
+    roles: Vec<u64>,
+    #[serde(default = "Default::default")]
+    servers: Option<String>,
+    operations: Vec<Operation>,
+}
+
+/// Grants some users and roles a set of operations on the servers matching a regex
+#[derive(Deserialize)]
+#[serde(try_from = "RawRule")]
+pub struct PermissionRule {
+    users: Vec<u64>,
+    roles: Vec<u64>,
+    servers: Option<Regex>,
+    operations: Vec<Operation>,
+}
+
+impl TryFrom<RawRule> for PermissionRule {
+    type Error = regex::Error;
+
+    fn try_from(raw: RawRule) -> Result<Self, Self::Error> {
+        Ok(Self {
+            users: raw.users,
+            roles: raw.roles,
+            // Anchored, so that `lobby` does not also grant access to `lobby-2`
+            servers: raw
+                .servers
+                .map(|servers| Regex::new(&format!("^(?:{})$", servers)))
+                .transpose()?,
+            operations: raw.operations,
+        })
+    }
+}
+
+impl PermissionRule {
+    fn applies_to(&self, invoker: &Invoker) -> bool {
+        self.users.contains(&invoker.user_id.get())
+            || invoker
+                .roles
+                .iter()
+                .any(|role| self.roles.contains(&role.get()))
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(transparent)]
+pub struct Permissions {
+    channels: HashMap<String, Vec<PermissionRule>>,
+}
+
+impl Permissions {
+    /// Whether `invoker` may perform `operation` on `server` from `channel_id`
+    pub fn allows(
+        &self,
+        channel_id: ChannelId,
+        invoker: &Invoker,
+        server: &str,
+        operation: Operation,
+    ) -> bool {
+        self.channels
+            .get(&channel_id.to_string())
+            .map(|rules| {
+                rules.iter().any(|rule| {
+                    rule.applies_to(invoker)
+                        && rule.operations.contains(&operation)
+                        && rule
+                            .servers
+                            .as_ref()
+                            .is_some_and(|servers| servers.is_match(server))
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Whether `invoker` may read the audit log of `channel_id`
+    pub fn allows_audit(&self, channel_id: ChannelId, invoker: &Invoker) -> bool {
+        self.channels
+            .get(&channel_id.to_string())
+            .map(|rules| {
+                rules.iter().any(|rule| {
+                    rule.applies_to(invoker) && rule.operations.contains(&Operation::Audit)
+                })
+            })
+            .unwrap_or(false)
+    }
+}
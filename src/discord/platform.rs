@@ -0,0 +1,349 @@
+//! Everything the bridge posts goes through [`ChatPlatform`], so flows can run without discord
+//!
+//! [`HttpClient`] is the real thing, [`Recorder`] keeps posts in memory for tests
+use futures::future::BoxFuture;
+use std::{error::Error, sync::Mutex};
+use tokio::sync::Notify;
+use twilight_http::Client as HttpClient;
+use twilight_model::{
+    application::{callback::InteractionResponse, command::Command, component::Component},
+    channel::{embed::Embed, message::AllowedMentions},
+    id::{ChannelId, InteractionId, MessageId, WebhookId},
+};
+
+pub type PlatformError = Box<dyn Error + Send + Sync>;
+
+/// A message posted through a webhook, under someone else's name and avatar
+#[derive(Clone, Debug)]
+pub struct WebhookMessage {
+    pub username: String,
+    pub content: String,
+    pub avatar_url: Option<String>,
+}
+
+/// Where the bridge posts messages and registers its commands
+pub trait ChatPlatform: Send + Sync {
+    /// Post an embed in `channel_id`
+    fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>>;
+
+    /// Post an embed in `channel_id` as a reply to `message_id`
+    fn reply(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>>;
+
+    /// Post an embed with buttons in `channel_id` as a reply to `message_id`
+    fn reply_with_components(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+        components: Vec<Component>,
+    ) -> BoxFuture<'_, Result<(), PlatformError>>;
+
+    /// Replace the slash commands users can invoke
+    fn register_commands(&self, commands: Vec<Command>)
+        -> BoxFuture<'_, Result<(), PlatformError>>;
+
+    /// Answer a slash command or button click
+    fn respond(
+        &self,
+        interaction_id: InteractionId,
+        token: String,
+        response: InteractionResponse,
+    ) -> BoxFuture<'_, Result<(), PlatformError>>;
+
+    /// The id and token of the webhook called `name` in `channel_id`, created if there is none
+    fn webhook(
+        &self,
+        channel_id: ChannelId,
+        name: &'static str,
+    ) -> BoxFuture<'_, Result<(WebhookId, String), PlatformError>>;
+
+    /// Post `message` through the webhook `webhook_id`
+    fn execute_webhook(
+        &self,
+        webhook_id: WebhookId,
+        token: String,
+        message: WebhookMessage,
+    ) -> BoxFuture<'_, Result<(), PlatformError>>;
+}
+
+impl ChatPlatform for HttpClient {
+    fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            self.create_message(channel_id)
+                .embeds(&[embed])?
+                .exec()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn reply(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            self.create_message(channel_id)
+                .reply(message_id)
+                .embeds(&[embed])?
+                .exec()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn reply_with_components(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+        components: Vec<Component>,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            self.create_message(channel_id)
+                .reply(message_id)
+                .embeds(&[embed])?
+                .components(&components)?
+                .exec()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn register_commands(
+        &self,
+        commands: Vec<Command>,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            self.set_global_commands(&commands)?.exec().await?;
+            Ok(())
+        })
+    }
+
+    fn respond(
+        &self,
+        interaction_id: InteractionId,
+        token: String,
+        response: InteractionResponse,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            self.interaction_callback(interaction_id, &token, &response)
+                .exec()
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn webhook(
+        &self,
+        channel_id: ChannelId,
+        name: &'static str,
+    ) -> BoxFuture<'_, Result<(WebhookId, String), PlatformError>> {
+        Box::pin(async move {
+            let existing = self
+                .channel_webhooks(channel_id)
+                .exec()
+                .await?
+                .models()
+                .await?
+                .into_iter()
+                .find(|webhook| webhook.name.as_deref() == Some(name) && webhook.token.is_some());
+
+            let webhook = match existing {
+                Some(webhook) => webhook,
+                None => {
+                    self.create_webhook(channel_id, name)
+                        .exec()
+                        .await?
+                        .model()
+                        .await?
+                }
+            };
+
+            match webhook.token {
+                Some(token) => Ok((webhook.id, token)),
+                None => Err(format!("Webhook in {} has no token", channel_id).into()),
+            }
+        })
+    }
+
+    fn execute_webhook(
+        &self,
+        webhook_id: WebhookId,
+        token: String,
+        message: WebhookMessage,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        Box::pin(async move {
+            let mut request = HttpClient::execute_webhook(self, webhook_id, &token)
+                .content(&message.content)
+                .username(&message.username)
+                // Players must not be able to ping anyone
+                .allowed_mentions(AllowedMentions::default());
+            if let Some(avatar_url) = &message.avatar_url {
+                request = request.avatar_url(avatar_url);
+            }
+            request.exec().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Something posted to a [`Recorder`]
+#[derive(Clone, Debug)]
+pub enum Post {
+    Embed {
+        channel_id: ChannelId,
+        embed: Embed,
+    },
+    Reply {
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+        /// Buttons posted with the embed, if any
+        components: Vec<Component>,
+    },
+    Commands(Vec<Command>),
+    Response {
+        interaction_id: InteractionId,
+        response: InteractionResponse,
+    },
+    /// Webhooks of a [`Recorder`] have the id of their channel
+    Webhook {
+        channel_id: ChannelId,
+        message: WebhookMessage,
+    },
+}
+
+impl Post {
+    /// The embed posted, if any
+    pub fn embed(&self) -> Option<&Embed> {
+        match self {
+            Post::Embed { embed, .. } | Post::Reply { embed, .. } => Some(embed),
+            Post::Response {
+                response:
+                    InteractionResponse::ChannelMessageWithSource(data)
+                    | InteractionResponse::UpdateMessage(data),
+                ..
+            } => data.embeds.first(),
+            Post::Commands(_) | Post::Response { .. } | Post::Webhook { .. } => None,
+        }
+    }
+}
+
+/// A platform that keeps everything posted to it, for tests
+#[derive(Default)]
+pub struct Recorder {
+    posts: Mutex<Vec<Post>>,
+    posted: Notify,
+}
+
+impl Recorder {
+    /// Everything posted so far, oldest first
+    pub fn posts(&self) -> Vec<Post> {
+        self.posts.lock().unwrap().clone()
+    }
+
+    /// Wait until at least `count` posts have been made, and return them
+    pub async fn wait_for(&self, count: usize) -> Vec<Post> {
+        loop {
+            let posted = self.posted.notified();
+            let posts = self.posts();
+            if posts.len() >= count {
+                return posts;
+            }
+            posted.await;
+        }
+    }
+
+    fn record(&self, post: Post) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.posts.lock().unwrap().push(post);
+        self.posted.notify_waiters();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl ChatPlatform for Recorder {
+    fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.record(Post::Embed { channel_id, embed })
+    }
+
+    fn reply(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.reply_with_components(channel_id, message_id, embed, vec![])
+    }
+
+    fn reply_with_components(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        embed: Embed,
+        components: Vec<Component>,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.record(Post::Reply {
+            channel_id,
+            message_id,
+            embed,
+            components,
+        })
+    }
+
+    fn register_commands(
+        &self,
+        commands: Vec<Command>,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.record(Post::Commands(commands))
+    }
+    fn respond(
+        &self,
+        interaction_id: InteractionId,
+        _token: String,
+        response: InteractionResponse,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.record(Post::Response {
+            interaction_id,
+            response,
+        })
+    }
+
+    fn webhook(
+        &self,
+        channel_id: ChannelId,
+        _name: &'static str,
+    ) -> BoxFuture<'_, Result<(WebhookId, String), PlatformError>> {
+        Box::pin(async move { Ok((WebhookId(channel_id.0), String::new())) })
+    }
+
+    fn execute_webhook(
+        &self,
+        webhook_id: WebhookId,
+        _token: String,
+        message: WebhookMessage,
+    ) -> BoxFuture<'_, Result<(), PlatformError>> {
+        self.record(Post::Webhook {
+            channel_id: ChannelId(webhook_id.0),
+            message,
+        })
+    }
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, default::Default};
+use tc_discord_protocol::Exec;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct ServerCommand {
     #[serde(skip_serializing)]
     pub on: String,
@@ -11,4 +12,18 @@ pub struct ServerCommand {
     pub query: Vec<String>,
     #[serde(default = "Default::default")]
     pub set: HashMap<String, String>,
+    /// Only reply with the servers `on` selects, without sending anything
+    #[serde(default = "Default::default")]
+    pub dry_run: bool,
+}
+
+impl ServerCommand {
+    /// What is sent to each server matching `on`
+    pub fn exec(&self) -> Exec {
+        Exec {
+            run: self.run.clone(),
+            query: self.query.clone(),
+            set: self.set.clone(),
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! Posts events reported by plugins, like players joining or the server crashing, to discord
+use crate::{
+    discord::{
+        create_embed,
+        notifications::{notification_channel, post},
+        platform::ChatPlatform,
+    },
+    ws::{ServerEvent, ServerEventKind, ServerEventType},
+};
+use log::{error, warn};
+use std::collections::HashMap;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use twilight_embed_builder::{EmbedError, EmbedFieldBuilder};
+use twilight_model::{
+    channel::embed::{Embed, EmbedField},
+    id::ChannelId,
+};
+
+/// Post every event from `events` that passes its control channel's filter, until the publisher
+/// goes away
+pub async fn run(
+    platform: &dyn ChatPlatform,
+    mut events: Receiver<ServerEvent>,
+    notification_channels: HashMap<String, ChannelId>,
+    event_filters: HashMap<String, Vec<ServerEventType>>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Missed {} server events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if let Some(filter) = event_filters.get(&event.ctrl_channel_id) {
+            if !filter.contains(&ServerEventType::of(&event.kind)) {
+                continue;
+            }
+        }
+
+        let channel_id = match notification_channel(&notification_channels, &event.ctrl_channel_id)
+        {
+            Some(channel_id) => channel_id,
+            None => continue,
+        };
+
+        match server_event_embed(&event) {
+            Ok(embed) => post(platform, channel_id, embed, &event.name).await,
+            Err(err) => error!("Error building server event for {}, {}", event.name, err),
+        }
+    }
+}
+
+/// Build the embed posted for a server event
+pub fn server_event_embed(event: &ServerEvent) -> Result<Embed, EmbedError> {
+    let name = Some(event.name.as_str());
+    match &event.kind {
+        ServerEventKind::PlayerJoined { player } => {
+            create_embed(&format!("{} joined", player), name, vec![])
+        }
+        ServerEventKind::PlayerLeft { player } => {
+            create_embed(&format!("{} left", player), name, vec![])
+        }
+        ServerEventKind::PlayerDied { player, message } => create_embed(
+            &format!("{} died", player),
+            name,
+            optional_field("Message", message),
+        ),
+        ServerEventKind::PlayerKicked { player, reason } => create_embed(
+            &format!("{} was kicked", player),
+            name,
+            optional_field("Reason", reason),
+        ),
+        ServerEventKind::Starting => create_embed("Server starting", name, vec![]),
+        ServerEventKind::Stopping => create_embed("Server stopping", name, vec![]),
+        ServerEventKind::Crashed { reason } => create_embed(
+            ":boom: Server crashed",
+            name,
+            optional_field("Reason", reason),
+        ),
+    }
+}
+
+fn optional_field(name: &str, value: &Option<String>) -> Vec<EmbedField> {
+    match value {
+        Some(value) if !value.is_empty() => vec![EmbedFieldBuilder::new(name, value).build()],
+        _ => vec![],
+    }
+}
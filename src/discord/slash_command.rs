@@ -0,0 +1,344 @@
+use crate::{
+    audit::{AuditLog, CommandRecord, Outcome, ResponseRecord},
+    discord::{
+        confirmation::Confirmations,
+        create_embed, create_error_embed, dispatch_server_command, dry_run_embed,
+        list_servers_embed,
+        permissions::{Invoker, Permissions},
+        platform::ChatPlatform,
+        server_command::ServerCommand,
+    },
+    ws::{Am, WsManager},
+};
+use std::error::Error;
+use twilight_embed_builder::{EmbedError, EmbedFieldBuilder};
+use twilight_model::{
+    application::{
+        callback::{CallbackData, InteractionResponse},
+        command::{
+            BaseCommandOptionData, ChoiceCommandOptionData, Command, CommandOption, CommandType,
+        },
+        interaction::{
+            application_command::{CommandData, CommandOptionValue},
+            ApplicationCommand,
+        },
+    },
+    channel::embed::{Embed, EmbedField},
+};
+
+/// How many audit log entries are shown per page of `/audit`
+const AUDIT_PAGE_SIZE: usize = 10;
+/// Discord rejects embed fields with values longer than this
+const MAX_FIELD_VALUE_LENGTH: usize = 1024;
+
+/// Every slash command the bot registers with discord
+pub fn commands() -> Vec<Command> {
+    vec![
+        command(
+            "list",
+            "List the servers controlled from this channel",
+            vec![],
+        ),
+        command(
+            "run",
+            "Run a command on one or more servers",
+            vec![
+                on_option(),
+                string_option("command", "The command to run"),
+                dry_run_option(),
+            ],
+        ),
+        command(
+            "query",
+            "Query a value from one or more servers",
+            vec![
+                on_option(),
+                string_option("key", "The value to query"),
+                dry_run_option(),
+            ],
+        ),
+        command(
+            "set",
+            "Set a value on one or more servers",
+            vec![
+                on_option(),
+                string_option("key", "The value to set"),
+                string_option("value", "What to set it to"),
+                dry_run_option(),
+            ],
+        ),
+        command(
+            "audit",
+            "Show recent commands sent from this channel",
+            vec![CommandOption::Integer(ChoiceCommandOptionData {
+                choices: vec![],
+                description: "Page to show, starting at 1 for the newest commands".to_string(),
+                name: "page".to_string(),
+                required: false,
+            })],
+        ),
+    ]
+}
+
+fn command(name: &str, description: &str, options: Vec<CommandOption>) -> Command {
+    Command {
+        application_id: None,
+        guild_id: None,
+        name: name.to_string(),
+        default_permission: None,
+        description: description.to_string(),
+        id: None,
+        kind: CommandType::ChatInput,
+        options,
+    }
+}
+
+fn string_option(name: &str, description: &str) -> CommandOption {
+    CommandOption::String(ChoiceCommandOptionData {
+        choices: vec![],
+        description: description.to_string(),
+        name: name.to_string(),
+        required: true,
+    })
+}
+
+/// The same server selector as the `on` field of a YAML command
+fn on_option() -> CommandOption {
+    string_option(
+        "on",
+        "Servers to target, a name, exact:, glob: or regex: pattern, or terms like tag:region=eu",
+    )
+}
+
+/// The same as `dry_run` in a YAML command
+fn dry_run_option() -> CommandOption {
+    CommandOption::Boolean(BaseCommandOptionData {
+        description: "Only list the servers that would be targeted".to_string(),
+        name: "dry_run".to_string(),
+        required: false,
+    })
+}
+
+/// Turn a `/run`, `/query` or `/set` invocation into the equivalent YAML server command
+fn to_server_command(data: &CommandData) -> Result<ServerCommand, String> {
+    let option = |name: &str| {
+        data.options
+            .iter()
+            .find(|option| option.name == name)
+            .and_then(|option| match &option.value {
+                CommandOptionValue::String(value) => Some(value.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| format!("Missing option {}", name))
+    };
+
+    let mut executable = ServerCommand {
+        on: option("on")?,
+        dry_run: data.options.iter().any(|option| {
+            option.name == "dry_run" && matches!(option.value, CommandOptionValue::Boolean(true))
+        }),
+        ..Default::default()
+    };
+    match data.name.as_str() {
+        "run" => executable.run.push(option("command")?),
+        "query" => executable.query.push(option("key")?),
+        "set" => {
+            executable.set.insert(option("key")?, option("value")?);
+        }
+        name => return Err(format!("Unknown command {}", name)),
+    }
+
+    Ok(executable)
+}
+
+/// The user who invoked `command`, with their roles if it was invoked in a guild
+fn invoker(command: &ApplicationCommand) -> Option<Invoker> {
+    match &command.member {
+        Some(member) => Some(Invoker {
+            user_id: member.user.as_ref()?.id,
+            roles: member.roles.clone(),
+        }),
+        None => Some(Invoker {
+            user_id: command.user.as_ref()?.id,
+            roles: vec![],
+        }),
+    }
+}
+
+pub async fn handle_interaction(
+    command: &ApplicationCommand,
+    platform: &dyn ChatPlatform,
+    ws_mgr: Am<WsManager>,
+    permissions: &Permissions,
+    audit: &AuditLog,
+    confirmations: &Confirmations,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Buttons, when the command needs confirming
+    let mut components = None;
+    let embed = match command.data.name.as_str() {
+        "list" => list_servers_embed(&ws_mgr, command.channel_id).await?,
+        "audit" => match invoker(command) {
+            Some(invoker) if permissions.allows_audit(command.channel_id, &invoker) => {
+                let page = command
+                    .data
+                    .options
+                    .iter()
+                    .find(|option| option.name == "page")
+                    .and_then(|option| match option.value {
+                        CommandOptionValue::Integer(page) => Some(page.max(1) as usize),
+                        _ => None,
+                    })
+                    .unwrap_or(1);
+                let records = audit
+                    .recent_commands(
+                        command.channel_id.get(),
+                        (page - 1) * AUDIT_PAGE_SIZE,
+                        AUDIT_PAGE_SIZE,
+                    )
+                    .await?;
+                audit_embed(page, records)?
+            }
+            _ => create_error_embed(
+                "Permission denied",
+                "You are not allowed to read the audit log for this channel",
+            )?,
+        },
+        _ => match to_server_command(&command.data) {
+            Ok(executable) => match invoker(command) {
+                Some(invoker) => match confirmations
+                    .hold(
+                        &ws_mgr,
+                        permissions,
+                        &invoker,
+                        &executable,
+                        command.channel_id,
+                    )
+                    .await?
+                {
+                    Some((embed, buttons)) => {
+                        components = Some(buttons);
+                        embed
+                    }
+                    None => match dispatch_server_command(
+                        &ws_mgr,
+                        permissions,
+                        audit,
+                        Some(&invoker),
+                        &executable,
+                        command.channel_id,
+                        None,
+                    )
+                    .await
+                    {
+                        Ok(dispatched) if executable.dry_run => dry_run_embed(&dispatched.servers)?,
+                        Ok(dispatched) => create_embed("Command sent", None, dispatched.fields())?,
+                        Err(err) => err.embed(&executable)?,
+                    },
+                },
+                None => create_error_embed("Permission denied", "Could not identify who you are")?,
+            },
+            Err(err) => create_error_embed("Error parsing command", &err)?,
+        },
+    };
+
+    platform
+        .respond(
+            command.id,
+            command.token.clone(),
+            InteractionResponse::ChannelMessageWithSource(CallbackData {
+                allowed_mentions: None,
+                components,
+                content: None,
+                embeds: vec![embed],
+                flags: None,
+                tts: None,
+            }),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// One page of the audit log, one field per command
+fn audit_embed(
+    page: usize,
+    records: Vec<(CommandRecord, Vec<ResponseRecord>)>,
+) -> Result<Embed, EmbedError> {
+    if records.is_empty() {
+        return create_embed(
+            &format!("Audit log, page {}", page),
+            None,
+            vec![
+                EmbedFieldBuilder::new("No commands", "Nothing has been sent from this channel")
+                    .build(),
+            ],
+        );
+    }
+
+    create_embed(
+        &format!("Audit log, page {}", page),
+        None,
+        records
+            .into_iter()
+            .map(|(record, responses)| audit_field(record, responses))
+            .collect(),
+    )
+}
+
+fn audit_field(record: CommandRecord, responses: Vec<ResponseRecord>) -> EmbedField {
+    let mut lines = vec![format!("<@{}> <t:{}:R>", record.user_id, record.timestamp)];
+    if !record.run.is_empty() {
+        lines.push(format!("Run: `{}`", record.run.join("`, `")));
+    }
+    if !record.query.is_empty() {
+        lines.push(format!("Query: `{}`", record.query.join("`, `")));
+    }
+    if !record.set.is_empty() {
+        let set = record
+            .set
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>();
+        lines.push(format!("Set: `{}`", set.join("`, `")));
+    }
+
+    let servers = record
+        .servers
+        .iter()
+        .map(|server| server.name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    lines.push(match record.outcome {
+        Outcome::Sent { failed } if failed.is_empty() => format!("Sent to {}", servers),
+        Outcome::Sent { failed } => {
+            format!("Sent to {}, failed for {}", servers, failed.join(", "))
+        }
+        Outcome::InvalidSelector { error } => format!("Invalid selector, {}", error),
+        Outcome::NoServers => "No servers matched".to_string(),
+        Outcome::PermissionDenied { denied } => format!("Denied for {}", denied.join(", ")),
+        Outcome::Held { reason } => {
+            format!(
+                "Held for confirmation ({}), would go to {}",
+                reason, servers
+            )
+        }
+        Outcome::Cancelled { by } => format!("Cancelled by <@{}>", by),
+        Outcome::Expired => "Not confirmed in time".to_string(),
+        Outcome::TargetsChanged => "Not sent, the servers changed before confirming".to_string(),
+    });
+    if !responses.is_empty() {
+        let responded = responses
+            .iter()
+            .map(|response| response.server.name.as_str())
+            .collect::<Vec<&str>>();
+        lines.push(format!("Responses from {}", responded.join(", ")));
+    }
+
+    let mut value = lines.join("\n");
+    if value.chars().count() > MAX_FIELD_VALUE_LENGTH {
+        value = value.chars().take(MAX_FIELD_VALUE_LENGTH - 1).collect();
+        value.push('…');
+    }
+
+    EmbedFieldBuilder::new(format!("on `{}`", record.on), value).build()
+}
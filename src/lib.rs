@@ -0,0 +1,7 @@
+//! Bridges game servers, connected over websockets, with discord
+pub mod admin;
+pub mod audit;
+pub mod config;
+pub mod discord;
+pub mod metrics;
+pub mod ws;
@@ -1,15 +1,68 @@
-pub mod discord;
-pub mod ws;
-
-use std::sync::Arc;
+use log::{error, info};
+use std::{env, process, sync::Arc};
+use tc_discord::{admin, audit, config, discord, metrics, ws};
+use tc_discord_protocol::schema;
 use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() {
-    dotenv::dotenv().expect("Failed to load .env file");
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+    // `tc-discord schema` prints the packet schema, for generating plugin code from
+    if env::args().nth(1).as_deref() == Some("schema") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&schema::schema()).unwrap()
+        );
+        return;
+    }
+
+    // Secrets in the config file can still come from the environment, which .env can fill in
+    dotenv::dotenv().ok();
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    log4rs::init_file(&config.log_config, Default::default()).unwrap();
+
+    let audit = Arc::new(audit::AuditLog::new(config.audit_log.clone()));
+    let metrics = Arc::new(metrics::Metrics::default());
+    let manager = Arc::new(Mutex::new(
+        ws::WsManager::new(&config, audit.clone(), metrics.clone()).await,
+    ));
+
+    if let Some(admin) = config.admin {
+        let (manager, audit) = (manager.clone(), audit.clone());
+        tokio::spawn(async move {
+            if let Err(err) = admin::serve(admin, manager, audit).await {
+                error!("Admin API stopped, {}", err);
+            }
+        });
+    }
 
-    let manager = ws::WsManager::new().await;
+    if let Some(metrics_config) = config.metrics {
+        let (manager, metrics) = (manager.clone(), metrics.clone());
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_config, metrics, manager).await {
+                error!("Metrics endpoint stopped, {}", err);
+            }
+        });
+    }
 
-    discord::main(Arc::new(Mutex::new(manager))).await.unwrap();
+    // Returns once a shutdown signal has been handled, or discord failed
+    if let Err(err) = discord::main(
+        manager,
+        audit,
+        config.discord,
+        config.permissions,
+        config.confirmation,
+        metrics,
+    )
+    .await
+    {
+        error!("{}", err);
+        process::exit(1);
+    }
+    info!("Shut down");
 }
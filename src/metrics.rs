@@ -0,0 +1,292 @@
+//! Prometheus metrics, served in the text exposition format on `/metrics`
+//!
+//! Counters are kept here as they happen, the connected server gauges are read from the
+//! [`WsManager`] on every scrape
+use crate::ws::{Am, WsManager};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the command round-trip latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address `/metrics` is served on
+    pub bind: SocketAddr,
+}
+
+/// A counter split by the value of a single label
+#[derive(Default)]
+struct LabeledCounter(Mutex<BTreeMap<String, u64>>);
+
+impl LabeledCounter {
+    fn inc(&self, label: &str) {
+        *self.0.lock().unwrap().entry(label.to_string()).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        header(out, name, help, "counter");
+        for (value, count) in self.0.lock().unwrap().iter() {
+            writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(value), count).unwrap();
+        }
+    }
+}
+
+/// Cumulative buckets for [`LATENCY_BUCKETS`], plus `+Inf`
+struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// In microseconds, so it can be kept in an atomic
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        )
+        .unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+    }
+}
+
+/// Everything the bridge counts, shared by the websocket and discord sides
+#[derive(Default)]
+pub struct Metrics {
+    packets_received: LabeledCounter,
+    packets_sent: LabeledCounter,
+    packet_errors: LabeledCounter,
+    discord_events: LabeledCounter,
+    commands_sent: AtomicU64,
+    command_send_failures: AtomicU64,
+    command_latency: Histogram,
+}
+
+impl Metrics {
+    /// A packet arrived from a plugin, `packet` is its variant name
+    pub fn packet_received(&self, packet: &str) {
+        self.packets_received.inc(packet);
+    }
+
+    /// A packet was written to a plugin's socket, `packet` is its variant name
+    pub fn packet_sent(&self, packet: &str) {
+        self.packets_sent.inc(packet);
+    }
+
+    /// An error packet was sent back to a plugin
+    pub fn packet_error(&self, error: &str) {
+        self.packet_errors.inc(error);
+    }
+
+    pub fn discord_event(&self, shard_id: u64) {
+        self.discord_events.inc(&shard_id.to_string());
+    }
+
+    /// A `ServerRun` packet was queued for a server, or couldn't be
+    pub fn command_sent(&self, success: bool) {
+        if success {
+            self.commands_sent.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.command_send_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// How long a server took to respond to a command
+    pub fn command_responded(&self, latency: Duration) {
+        self.command_latency.observe(latency);
+    }
+
+    /// Every metric, with `connected` holding the number of servers per control channel
+    pub fn render(&self, connected: &BTreeMap<String, usize>) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "tc_discord_connected_servers",
+            "Servers connected to the bridge, by control channel",
+            "gauge",
+        );
+        for (ctrl_channel_id, count) in connected {
+            writeln!(
+                out,
+                "tc_discord_connected_servers{{ctrl_channel_id=\"{}\"}} {}",
+                escape(ctrl_channel_id),
+                count
+            )
+            .unwrap();
+        }
+
+        self.packets_received.render(
+            &mut out,
+            "tc_discord_packets_received_total",
+            "Packets received from plugins, by packet",
+            "packet",
+        );
+        self.packets_sent.render(
+            &mut out,
+            "tc_discord_packets_sent_total",
+            "Packets sent to plugins, by packet",
+            "packet",
+        );
+        self.packet_errors.render(
+            &mut out,
+            "tc_discord_packet_errors_total",
+            "Error packets sent to plugins, by error type",
+            "error",
+        );
+        self.discord_events.render(
+            &mut out,
+            "tc_discord_discord_events_total",
+            "Discord gateway events handled, by shard",
+            "shard",
+        );
+
+        header(
+            &mut out,
+            "tc_discord_commands_sent_total",
+            "Commands queued for a server",
+            "counter",
+        );
+        writeln!(
+            out,
+            "tc_discord_commands_sent_total {}",
+            self.commands_sent.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "tc_discord_command_send_failures_total",
+            "Commands that could not be queued for a server",
+            "counter",
+        );
+        writeln!(
+            out,
+            "tc_discord_command_send_failures_total {}",
+            self.command_send_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        self.command_latency.render(
+            &mut out,
+            "tc_discord_command_latency_seconds",
+            "Time between sending a command and the server responding to it",
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `/metrics` until the listener fails
+pub async fn serve(
+    config: MetricsConfig,
+    metrics: Arc<Metrics>,
+    ws_mgr: Am<WsManager>,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let (metrics, ws_mgr) = (metrics.clone(), ws_mgr.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(metrics.clone(), ws_mgr.clone(), req)
+            }))
+        }
+    });
+
+    info!("Serving metrics on {}", config.bind);
+    Server::bind(&config.bind).serve(make_service).await
+}
+
+async fn handle(
+    metrics: Arc<Metrics>,
+    ws_mgr: Am<WsManager>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    // Servers count once they have a control channel, and no longer while suspended
+    let servers = ws_mgr.lock().await.get_connected();
+    let mut connected = BTreeMap::new();
+    for (info, client) in servers {
+        if info.ctrl_channel_id.is_empty() || client.lock().await.is_suspended() {
+            continue;
+        }
+        *connected.entry(info.ctrl_channel_id).or_default() += 1;
+    }
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics.render(&connected)))
+        .unwrap())
+}
@@ -0,0 +1,36 @@
+//! Shared-secret authentication for plugin connections
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues challenges to plugins and checks their responses against the shared secret
+pub struct Authenticator {
+    secret: Vec<u8>,
+}
+
+impl Authenticator {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Generate a random, hex encoded challenge for a plugin to sign
+    pub fn challenge(&self) -> String {
+        hex::encode(rand::random::<[u8; 32]>())
+    }
+
+    /// Check that `response` is the hex encoded HMAC-SHA256 of `challenge`, keyed with the secret
+    pub fn verify(&self, challenge: &str, response: &str) -> bool {
+        let response = match hex::decode(response) {
+            Ok(response) => response,
+            Err(_) => return false,
+        };
+
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(challenge.as_bytes());
+        mac.verify(&response).is_ok()
+    }
+}
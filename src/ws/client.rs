@@ -1,139 +1,453 @@
 use crate::{
-    discord::{create_embed, server_command::ServerCommand},
-    ws::packets::{ErrorType, IncomingPacket, OutgoingPacket},
+    audit::{self, AuditLog, AuditRecord, AuditServer, ResponseRecord},
+    discord::{create_embed, field_value, platform::ChatPlatform, server_command::ServerCommand},
+    metrics::Metrics,
+    ws::{
+        auth::Authenticator,
+        packets::{
+            ChatMessagePacket, CommandResponsePacket, ErrorType, IncomingPacket, OutgoingPacket,
+            CAPABILITIES, CAPABILITY_CHAT, CAPABILITY_COMMAND_RESPONSE, CAPABILITY_RESUME,
+            MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        },
+        directory::{Directory, ServerInfo},
+        registry::{Claim, Registry},
+        ChatEvent, EventBus, HeartbeatConfig, LifecycleEvent, LifecycleEventKind,
+        PluginStream, ServerEvent, Shared,
+    },
+};
+use futures::{prelude::*, stream::SplitSink};
+use log::{debug, info, error, warn};
+use rand::RngCore;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use futures::prelude::*;
-use log::{debug, info, error};
-use std::sync::Arc;
 use tokio::{
-    net::TcpStream,
     sync::{
-        mpsc::{error::SendError, Receiver, Sender},
-        Mutex,
+        mpsc::{error::SendError, UnboundedReceiver, UnboundedSender},
+        Mutex, Notify,
     },
 };
-use tokio_tungstenite::tungstenite::Message;
-use twilight_http::client::Client as HttpClient;
-use twilight_model::id::ChannelId;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use twilight_embed_builder::EmbedFieldBuilder;
+use twilight_model::{channel::embed::EmbedField, id::ChannelId};
 use uuid::Uuid;
 
+/// How long a plugin has to answer the auth challenge before it is disconnected
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a `ServerRun` packet waits for a response before it is forgotten
+const PENDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+/// How many packets a resumable session keeps for replay before dropping the oldest
+const MAX_UNACKED_PACKETS: usize = 256;
+/// Discord rejects embeds with more fields than this
+const MAX_EMBED_FIELDS: usize = 25;
+
+/// A server's metadata along with the state of its connection
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerDetails {
+    #[serde(flatten)]
+    pub info: ServerInfo,
+    pub authenticated: bool,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+    pub pending_requests: usize,
+    pub resumable: bool,
+}
+
+/// A `ServerRun` packet that has been sent, but not yet responded to
+#[derive(Clone)]
+struct PendingRequest {
+    channel_id: ChannelId,
+    command: ServerCommand,
+    sent_at: Instant,
+}
+
 #[derive(Clone)]
 pub struct WsClient {
-    outgoing_stream: Sender<OutgoingPacket>,
+    outgoing_stream: UnboundedSender<OutgoingPacket>,
     pub(super) name: String,
     pub(super) ctrl_channel_id: String,
+    pub(super) bridge_channel_id: String,
+    tags: BTreeMap<String, String>,
     uuid: Uuid,
+    /// Unlike `uuid`, which a reconnecting server takes back, this never changes
+    connection_id: Uuid,
+    /// Fingerprint of the TLS client certificate the plugin identified itself with
+    certificate_fingerprint: Option<String>,
     pub(super) alive: bool,
-    discord: Arc<HttpClient>,
+    /// Disconnected, waiting for the session to be resumed
+    suspended: bool,
+    platform: Arc<dyn ChatPlatform>,
+    pending: HashMap<Uuid, PendingRequest>,
+    authenticator: Arc<Authenticator>,
+    challenge: String,
+    authenticated: bool,
+    protocol_version: u32,
+    capabilities: HashSet<String>,
+    audit: Arc<AuditLog>,
+    events: EventBus,
+    registry: Arc<Registry>,
+    directory: Arc<Directory>,
+    metrics: Arc<Metrics>,
+    /// Fires when the connection should be closed, because it was replaced or kicked
+    disconnect: Arc<Notify>,
+    kicked: bool,
+    /// Set when the bridge is shutting down, how long the plugin should wait before reconnecting
+    going_away: Option<Duration>,
+    /// Set once a server that supports resuming is registered
+    resume_token: Option<String>,
+    next_seq: u64,
+    /// Packets sent on a resumable session that the plugin hasn't acknowledged yet
+    unacked: VecDeque<(u64, OutgoingPacket)>,
+    /// Set when the session was just resumed, packets to send again right after `Resumed`
+    replay: Option<Vec<(u64, OutgoingPacket)>>,
 }
 
 impl WsClient {
     /// Scaffold out a new client and start the main event loop
     pub(super) async fn new(
         uuid: Uuid,
-        get_http: Arc<HttpClient>,
-        stream: TcpStream,
+        platform: Arc<dyn ChatPlatform>,
+        shared: Shared,
+        stream: Box<dyn PluginStream>,
+        certificate_fingerprint: Option<String>,
     ) -> Arc<Mutex<WsClient>> {
-        let (outgoing_stream, incoming_stream) = tokio::sync::mpsc::channel::<OutgoingPacket>(16);
+        // Unbounded, packets are queued with the client locked and the writer locks it to drain
+        // them, so a full queue would never empty
+        let (outgoing_stream, incoming_stream) =
+            tokio::sync::mpsc::unbounded_channel::<OutgoingPacket>();
+        let disconnect = shared.registry.register(uuid);
 
-        let gamer = Arc::new(Mutex::new(Self {
+        let client = Self {
             outgoing_stream,
             name: Default::default(),
             ctrl_channel_id: Default::default(),
+            bridge_channel_id: Default::default(),
+            tags: Default::default(),
             uuid,
+            connection_id: uuid,
+            certificate_fingerprint,
             alive: true,
-            discord: get_http,
-        }));
+            suspended: false,
+            platform,
+            pending: HashMap::new(),
+            challenge: shared.authenticator.challenge(),
+            authenticator: shared.authenticator,
+            authenticated: false,
+            protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: HashSet::new(),
+            audit: shared.audit,
+            events: shared.events,
+            registry: shared.registry,
+            directory: shared.directory.clone(),
+            metrics: shared.metrics,
+            disconnect: disconnect.clone(),
+            kicked: false,
+            going_away: None,
+            resume_token: None,
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            replay: None,
+        };
+        let info = client.info();
+        let gamer = Arc::new(Mutex::new(client));
 
-        tokio::spawn(Self::main_loop(gamer.clone(), stream, incoming_stream));
+        shared.directory.insert(uuid, info, gamer.clone());
+        tokio::spawn(Self::main_loop(
+            gamer.clone(),
+            stream,
+            incoming_stream,
+            shared.heartbeat,
+            shared.resume.grace_period,
+            disconnect,
+            LiveConnection::new(shared.live),
+        ));
 
         gamer
     }
 
     async fn main_loop(
         this: Arc<Mutex<Self>>,
-        stream: TcpStream,
-        mut incoming_stream: Receiver<OutgoingPacket>,
+        stream: Box<dyn PluginStream>,
+        mut incoming_stream: UnboundedReceiver<OutgoingPacket>,
+        heartbeat_config: HeartbeatConfig,
+        grace_period: Duration,
+        disconnect: Arc<Notify>,
+        _live: LiveConnection,
     ) {
         debug!("Upgrading client");
-        let ws_stream = tokio_tungstenite::accept_async(stream)
-            .await
-            .expect("Error during the websocket handshake occurred");
+        let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+            Ok(ws_stream) => ws_stream,
+            Err(err) => {
+                error!("Error during the websocket handshake, {}", err);
+                this.lock().await.kill();
+                return;
+            }
+        };
 
         let (mut sender, mut receiver) = ws_stream.split();
 
         debug!("Upgraded client");
 
+        let (challenge, metrics) = {
+            let lock = this.lock().await;
+            (lock.challenge.clone(), lock.metrics.clone())
+        };
+        let challenge = OutgoingPacket::AuthChallenge(challenge);
+        let serialized = serde_json::to_string(&challenge.versioned(MIN_PROTOCOL_VERSION)).unwrap();
+        count_sent(&metrics, &challenge);
+        if sender.send(Message::Text(serialized)).await.is_err() {
+            error!("Error sending auth challenge");
+            this.lock().await.kill();
+            return;
+        }
+
+        let auth_timeout = tokio::time::sleep(AUTH_TIMEOUT);
+        tokio::pin!(auth_timeout);
+        let mut heartbeat = tokio::time::interval(heartbeat_config.interval);
+        // Anything the plugin sends counts as a heartbeat, not just pongs
+        let mut last_seen = Instant::now();
+        // Set when a session that can be resumed loses its connection
+        let mut suspend = false;
+
         loop {
+            let authenticated = this.lock().await.authenticated;
             tokio::select! {
                 packet = receiver.next() => {
-                    if let Some(Ok(Message::Text(message))) = packet {
-                        let mut lock = this.lock().await;
-                        lock.handle_packet(message).await;
-                    } else {
-                        let mut lock = this.lock().await;
-                        lock.kill();
-                        match sender.send(Message::Close(None)).await {
-                            Err(_) => {
-                                error!("Error sending close message");
-                            }
-                            _ => {}
-                        };
-                        match sender.close().await {
-                            Err(_) => {
-                                error!("Error closing server socket");
+                    let mut lock = this.lock().await;
+                    match packet {
+                        Some(Ok(Message::Text(message))) => {
+                            last_seen = Instant::now();
+                            lock.handle_packet(message).await;
+                            if lock.alive {
+                                lock.directory.update(lock.connection_id, lock.info());
+                                let replay = match lock.replay.take() {
+                                    Some(replay) => replay,
+                                    None => continue,
+                                };
+                                let version = lock.protocol_version;
+                                drop(lock);
+                                // Written here rather than queued, so nothing queued meanwhile
+                                // can get between `Resumed` and the packets it announces
+                                let resumed = OutgoingPacket::Resumed(replay.len());
+                                count_sent(&metrics, &resumed);
+                                if sender
+                                    .send(Message::Text(serialize(&resumed, version, None)))
+                                    .await
+                                    .is_err()
+                                {
+                                    error!("Error confirming the resumed session");
+                                    continue;
+                                }
+                                for (seq, packet) in replay {
+                                    let serialized = serialize(&packet, version, Some(seq));
+                                    count_sent(&metrics, &packet);
+                                    if sender.send(Message::Text(serialized)).await.is_err() {
+                                        error!("Error replaying packet {}", seq);
+                                        break;
+                                    }
+                                }
+                                continue;
                             }
-                            _ => {},
-                        };
+                        }
+                        // Pings are answered by tungstenite itself
+                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {
+                            last_seen = Instant::now();
+                            continue;
+                        }
+                        _ if lock.resume_token.is_some() => {
+                            info!("{} disconnected, waiting for it to resume", lock.uuid);
+                            suspend = true;
+                            break;
+                        }
+                        _ => lock.offline("Disconnected".to_string()),
+                    }
+                    let version = lock.protocol_version;
+                    drop(lock);
+                    Self::close(&mut sender, &mut incoming_stream, version, &metrics).await;
+                    break;
+                },
+                _ = &mut auth_timeout, if !authenticated => {
+                    let mut lock = this.lock().await;
+                    info!("{} did not authenticate in time", lock.uuid);
+                    lock.outgoing_stream
+                        .send(OutgoingPacket::Error(
+                            ErrorType::Unauthorized,
+                            "Authentication timed out".to_string(),
+                        ))
+                        .unwrap_or(());
+                    lock.kill();
+                    let version = lock.protocol_version;
+                    drop(lock);
+                    Self::close(&mut sender, &mut incoming_stream, version, &metrics).await;
+                    break;
+                },
+                _ = disconnect.notified() => {
+                    let mut lock = this.lock().await;
+                    let (packet, reason) = lock.disconnect_reason();
+                    info!("Disconnecting {}, {}", lock.uuid, reason);
+                    if let Some(packet) = packet {
+                        lock.outgoing_stream.send(packet).unwrap_or(());
+                    }
+                    lock.offline(reason.to_string());
+                    let version = lock.protocol_version;
+                    drop(lock);
+                    Self::close(&mut sender, &mut incoming_stream, version, &metrics).await;
+                    break;
+                },
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() >= heartbeat_config.timeout() {
+                        let mut lock = this.lock().await;
+                        info!(
+                            "{} missed {} heartbeats",
+                            lock.uuid, heartbeat_config.missed_beats
+                        );
+                        if lock.resume_token.is_some() {
+                            drop(lock);
+                            suspend = true;
+                            tokio::time::timeout(heartbeat_config.interval, sender.close())
+                                .await
+                                .ok();
+                            break;
+                        }
+                        lock.offline(format!(
+                            "No heartbeat for {} seconds",
+                            heartbeat_config.timeout().as_secs()
+                        ));
+                        let version = lock.protocol_version;
+                        drop(lock);
+                        // The other end is most likely gone, so don't wait forever on the close
+                        tokio::time::timeout(
+                            heartbeat_config.interval,
+                            Self::close(&mut sender, &mut incoming_stream, version, &metrics),
+                        )
+                        .await
+                        .unwrap_or(());
                         break;
                     }
+                    if sender.send(Message::Ping(vec![])).await.is_err() {
+                        error!("Error sending heartbeat to {}", this.lock().await.uuid);
+                    }
                 },
                 agree = incoming_stream.recv() => {
-                    let serialized = serde_json::to_string(&agree.unwrap()).unwrap();
-                    info!("Sending packet to {}", this.lock().await.uuid);
-                    sender.send(Message::Text(serialized)).await.unwrap();
+                    let mut lock = this.lock().await;
+                    let packet = agree.unwrap();
+                    let seq = lock.track(&packet);
+                    let serialized = serialize(&packet, lock.protocol_version, seq);
+                    count_sent(&metrics, &packet);
+                    info!("Sending packet to {}", lock.uuid);
+                    drop(lock);
+                    // A dead socket shows up on the receiving side as well, which ends the loop
+                    if sender.send(Message::Text(serialized)).await.is_err() {
+                        error!("Error sending packet to {}", this.lock().await.uuid);
+                    }
                 }
             }
         }
+
+        if suspend {
+            Self::suspend(this, incoming_stream, grace_period, disconnect).await;
+        }
+    }
+
+    /// Hold on to packets sent to a server that lost its connection, until it resumes the
+    /// session or the grace period runs out
+    async fn suspend(
+        this: Arc<Mutex<Self>>,
+        mut incoming_stream: UnboundedReceiver<OutgoingPacket>,
+        grace_period: Duration,
+        disconnect: Arc<Notify>,
+    ) {
+        let mut lock = this.lock().await;
+        lock.suspended = true;
+        let registry = lock.registry.clone();
+        let resume_token = lock.resume_token.clone().unwrap();
+        registry.suspend(resume_token.clone(), lock.connection_id, this.clone());
+        drop(lock);
+
+        let expiry = tokio::time::sleep(grace_period);
+        tokio::pin!(expiry);
+        loop {
+            tokio::select! {
+                packet = incoming_stream.recv() => {
+                    let packet = match packet {
+                        Some(packet) => packet,
+                        // The resuming client has taken over the sender
+                        None => break,
+                    };
+                    let mut lock = this.lock().await;
+                    if lock.alive {
+                        lock.track(&packet);
+                    } else {
+                        // Queued before the session was resumed, pass it on to the new connection
+                        lock.outgoing_stream.send(packet).unwrap_or(());
+                    }
+                },
+                _ = &mut expiry => {
+                    if registry.expire(&resume_token) {
+                        this.lock().await.offline("Disconnected".to_string());
+                    }
+                    break;
+                },
+                _ = disconnect.notified() => {
+                    // Otherwise it was resumed, and the loop ends once the queue is drained
+                    if registry.expire(&resume_token) {
+                        let mut lock = this.lock().await;
+                        let (_, reason) = lock.disconnect_reason();
+                        lock.offline(reason.to_string());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send any packets still queued for the server, then close the socket
+    async fn close(
+        sender: &mut SplitSink<WebSocketStream<Box<dyn PluginStream>>, Message>,
+        incoming_stream: &mut UnboundedReceiver<OutgoingPacket>,
+        version: u32,
+        metrics: &Metrics,
+    ) {
+        while let Ok(packet) = incoming_stream.try_recv() {
+            let serialized = serde_json::to_string(&packet.versioned(version)).unwrap();
+            count_sent(metrics, &packet);
+            if sender.send(Message::Text(serialized)).await.is_err() {
+                error!("Error flushing packets before close");
+                break;
+            }
+        }
+        if sender.send(Message::Close(None)).await.is_err() {
+            error!("Error sending close message");
+        }
+        if sender.close().await.is_err() {
+            error!("Error closing server socket");
+        }
     }
 
     async fn handle_packet(&mut self, packet: String) {
         let parsed = IncomingPacket::from(packet);
+        self.metrics.packet_received(parsed.name());
 
         match parsed {
-            IncomingPacket::SetName(new_name) => {
-                info!("Set name to: {} for {}", &new_name, self.uuid.to_string());
-                if !self.ctrl_channel_id.is_empty() {
-                    self.discord
-                        .create_message(ChannelId::new(632402187112153090).unwrap())
-                        .embeds(&[create_embed("Server online", Some(&self.name), vec![]).unwrap()])
-                        .unwrap()
-                        .exec()
-                        .await
-                        .unwrap();
-                }
-                self.name = new_name;
+            IncomingPacket::Auth(response) => {
+                self.authenticate(response).await;
             }
-            IncomingPacket::SetControlChannel(ctrl_channel_id) => {
-                info!("Set server to: {} for {}", &ctrl_channel_id, self.uuid.to_string());
-                if !self.name.is_empty() {
-                    self.discord
-                        .create_message(ChannelId::new(632402187112153090).unwrap())
-                        .embeds(&[create_embed("Server online", Some(&self.name), vec![]).unwrap()])
-                        .unwrap()
-                        .exec()
-                        .await
-                        .unwrap();
-                }
-                self.ctrl_channel_id = ctrl_channel_id;
+            IncomingPacket::Hello(version, capabilities) => {
+                self.negotiate(version, capabilities).await;
             }
             IncomingPacket::InvalidID => {
                 self.outgoing_stream
                     .send(OutgoingPacket::Error(
                         ErrorType::PacketInvalidID,
-                        format!("Invalid packet ID"),
+                        "Invalid packet ID".to_string(),
                     ))
-                    .await
                     .unwrap_or(());
             }
             IncomingPacket::Invalid(err) => {
@@ -143,27 +457,601 @@ impl WsClient {
                         ErrorType::PacketDeserializationError,
                         format!("{}", err),
                     ))
-                    .await
                     .unwrap_or(());
             }
+            // Nothing else is honoured until the plugin has proven it knows the secret
+            _ if !self.authenticated => {
+                self.outgoing_stream
+                    .send(OutgoingPacket::Error(
+                        ErrorType::Unauthorized,
+                        "Authenticate before sending other packets".to_string(),
+                    ))
+                    .unwrap_or(());
+            }
+            IncomingPacket::SetName(new_name) => {
+                let ctrl_channel_id = self.ctrl_channel_id.clone();
+                let new_name = match self.claim_name(new_name, &ctrl_channel_id).await {
+                    Some(new_name) => new_name,
+                    None => return,
+                };
+                info!("Set name to: {} for {}", &new_name, self.uuid.to_string());
+                let old_name = std::mem::replace(&mut self.name, new_name);
+                if !self.ctrl_channel_id.is_empty() && old_name != self.name {
+                    self.publish(if old_name.is_empty() {
+                        LifecycleEventKind::Online
+                    } else {
+                        LifecycleEventKind::Renamed { old_name }
+                    });
+                }
+                self.start_session().await;
+            }
+            IncomingPacket::SetControlChannel(ctrl_channel_id) => {
+                // Otherwise chat in the channel would be relayed to the game and run as commands
+                if !ctrl_channel_id.is_empty() && ctrl_channel_id == self.bridge_channel_id {
+                    self.outgoing_stream
+                        .send(OutgoingPacket::Error(
+                            ErrorType::InvalidBridgeChannel,
+                            "The control channel must not be the bridge channel".to_string(),
+                        ))
+                        .unwrap_or(());
+                    return;
+                }
+                let name = self.name.clone();
+                // The name may come back suffixed if it is taken in the new control channel
+                let new_name = match self.claim_name(name, &ctrl_channel_id).await {
+                    Some(new_name) => new_name,
+                    None => return,
+                };
+                info!("Set server to: {} for {}", &ctrl_channel_id, self.uuid.to_string());
+                let old_name = std::mem::replace(&mut self.name, new_name);
+                let old_ctrl_channel_id =
+                    std::mem::replace(&mut self.ctrl_channel_id, ctrl_channel_id);
+                if !self.name.is_empty() && old_ctrl_channel_id != self.ctrl_channel_id {
+                    let was_online = !old_ctrl_channel_id.is_empty();
+                    self.publish(if was_online {
+                        LifecycleEventKind::ControlChannelChanged {
+                            old_ctrl_channel_id,
+                        }
+                    } else {
+                        LifecycleEventKind::Online
+                    });
+                    if was_online && old_name != self.name {
+                        self.publish(LifecycleEventKind::Renamed { old_name });
+                    }
+                }
+                self.start_session().await;
+            }
+            IncomingPacket::CommandResponse(response) => {
+                self.handle_command_response(response).await;
+            }
+            IncomingPacket::SetServerId(server_id) => {
+                self.set_server_id(server_id);
+            }
+            IncomingPacket::Ack(seq) => {
+                self.unacked.retain(|(sent, _)| *sent > seq);
+            }
+            IncomingPacket::Resume(resume_token) => {
+                self.resume(resume_token).await;
+            }
+            IncomingPacket::SetBridgeChannel(bridge_channel_id) => {
+                if bridge_channel_id == self.ctrl_channel_id {
+                    self.outgoing_stream
+                        .send(OutgoingPacket::Error(
+                            ErrorType::InvalidBridgeChannel,
+                            "The bridge channel must not be the control channel".to_string(),
+                        ))
+                        .unwrap_or(());
+                    return;
+                }
+                info!(
+                    "Set bridge channel to: {} for {}",
+                    &bridge_channel_id,
+                    self.uuid.to_string()
+                );
+                self.bridge_channel_id = bridge_channel_id;
+            }
+            IncomingPacket::SetTags(tags) => {
+                info!("Set tags to: {:?} for {}", tags, self.uuid.to_string());
+                self.tags = tags;
+            }
+            IncomingPacket::ChatMessage(message) => {
+                self.handle_chat_message(message).await;
+            }
+            // Events from a server that isn't online yet have nowhere to go
+            IncomingPacket::ServerEvent(kind)
+                if self.name.is_empty() || self.ctrl_channel_id.is_empty() =>
+            {
+                debug!("Dropping {:?} from {}, it is not online yet", kind, self.uuid);
+            }
+            IncomingPacket::ServerEvent(kind) => {
+                self.events.publish_server(ServerEvent {
+                    uuid: self.uuid,
+                    name: self.name.clone(),
+                    ctrl_channel_id: self.ctrl_channel_id.clone(),
+                    kind,
+                });
+            }
+        }
+    }
+
+    /// Agree on the protocol version and capabilities used for the rest of the connection
+    async fn negotiate(&mut self, version: u32, capabilities: Vec<String>) {
+        if version < MIN_PROTOCOL_VERSION {
+            info!(
+                "{} speaks unsupported protocol version {}",
+                self.uuid, version
+            );
+            self.outgoing_stream
+                .send(OutgoingPacket::Error(
+                    ErrorType::IncompatibleVersion,
+                    format!(
+                        "Protocol version {} is not supported, the oldest supported version is {}",
+                        version, MIN_PROTOCOL_VERSION
+                    ),
+                ))
+                .unwrap_or(());
+            self.kill();
+            return;
+        }
+
+        self.protocol_version = version.min(PROTOCOL_VERSION);
+        self.capabilities = capabilities
+            .into_iter()
+            .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+            .collect();
+        info!(
+            "Negotiated protocol version {} for {}",
+            self.protocol_version, self.uuid
+        );
+
+        self.outgoing_stream
+            .send(OutgoingPacket::Hello(
+                self.protocol_version,
+                self.capabilities.iter().cloned().collect(),
+            ))
+            .unwrap_or(());
+        self.start_session().await;
+    }
+
+    /// Take `name` in `ctrl_channel_id`, returning the name the server ends up with, or `None`
+    /// if another server has it and the conflict policy says to keep the old name
+    async fn claim_name(&mut self, name: String, ctrl_channel_id: &str) -> Option<String> {
+        // Names only have to be unique once a server has both
+        if name.is_empty() || ctrl_channel_id.is_empty() {
+            return Some(name);
+        }
+
+        let message = match self.registry.claim(self.connection_id, ctrl_channel_id, &name) {
+            Claim::Granted => return Some(name),
+            Claim::Replaced => {
+                info!("{} took the name {} from another server", self.uuid, name);
+                return Some(name);
+            }
+            Claim::Suffixed(suffixed) => {
+                let message = format!(
+                    "Another server in this control channel is named {}, using {}",
+                    name, suffixed
+                );
+                self.send_name_conflict(message).await;
+                return Some(suffixed);
+            }
+            Claim::Rejected => format!(
+                "Another server in this control channel is named {}",
+                name
+            ),
+        };
+        info!("Rejected the name {} for {}", name, self.uuid);
+        self.send_name_conflict(message).await;
+        None
+    }
+
+    async fn send_name_conflict(&self, message: String) {
+        self.outgoing_stream
+            .send(OutgoingPacket::Error(ErrorType::NameConflict, message))
+            .unwrap_or(());
+    }
+
+    /// Take back the uuid the server had the last time it connected with `server_id`
+    fn set_server_id(&mut self, server_id: String) {
+        let old_uuid = match self
+            .registry
+            .reclaim(server_id, self.connection_id, self.uuid)
+        {
+            Some(old_uuid) if old_uuid != self.uuid => old_uuid,
+            _ => return,
+        };
+        info!("{} reconnected as {}", self.uuid, old_uuid);
+        self.uuid = old_uuid;
+    }
+
+    /// What the directory knows about this server
+    fn info(&self) -> ServerInfo {
+        ServerInfo {
+            uuid: self.uuid,
+            name: self.name.clone(),
+            ctrl_channel_id: self.ctrl_channel_id.clone(),
+            bridge_channel_id: self.bridge_channel_id.clone(),
+            tags: self.tags.clone(),
+            certificate_fingerprint: self.certificate_fingerprint.clone(),
+        }
+    }
+
+    /// Give a newly registered server that supports resuming a token to resume its session with
+    async fn start_session(&mut self) {
+        if self.resume_token.is_some()
+            || self.name.is_empty()
+            || self.ctrl_channel_id.is_empty()
+            || !self.capabilities.contains(CAPABILITY_RESUME)
+        {
+            return;
+        }
+
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let resume_token = hex::encode(token);
+        self.resume_token = Some(resume_token.clone());
+        self.outgoing_stream
+            .send(OutgoingPacket::Session(resume_token))
+            .unwrap_or(());
+    }
+
+    /// Take over the session of a client that lost its connection, replaying whatever it had
+    /// not acknowledged
+    async fn resume(&mut self, resume_token: String) {
+        let old = if self.capabilities.contains(CAPABILITY_RESUME) {
+            self.registry.resume(&resume_token, self.connection_id)
+        } else {
+            None
+        };
+        let old = match old {
+            Some(old) => old,
+            None => {
+                self.outgoing_stream
+                    .send(OutgoingPacket::Error(
+                        ErrorType::ResumeFailed,
+                        "Unknown or expired resume token".to_string(),
+                    ))
+                    .unwrap_or(());
+                return;
+            }
+        };
+
+        let mut old = old.lock().await;
+        info!("{} resumed the session of {}", self.uuid, old.uuid);
+        // Anything still sent to the old client ends up here
+        old.outgoing_stream = self.outgoing_stream.clone();
+        old.kill();
+        self.name = std::mem::take(&mut old.name);
+        self.ctrl_channel_id = std::mem::take(&mut old.ctrl_channel_id);
+        self.bridge_channel_id = std::mem::take(&mut old.bridge_channel_id);
+        self.tags = std::mem::take(&mut old.tags);
+        self.pending = std::mem::take(&mut old.pending);
+        self.unacked = std::mem::take(&mut old.unacked);
+        self.next_seq = old.next_seq;
+        let uuid = old.uuid;
+        drop(old);
+
+        self.uuid = uuid;
+        self.resume_token = Some(resume_token);
+        self.replay = Some(self.unacked.iter().cloned().collect());
+    }
+
+    /// Remember a packet until the plugin acknowledges it, returning its sequence number. Only
+    /// packets on resumable sessions are tracked
+    fn track(&mut self, packet: &OutgoingPacket) -> Option<u64> {
+        self.resume_token.as_ref()?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.unacked.push_back((seq, packet.clone()));
+        if self.unacked.len() > MAX_UNACKED_PACKETS {
+            warn!("Dropping the oldest unacknowledged packet for {}", self.uuid);
+            self.unacked.pop_front();
+        }
+        Some(seq)
+    }
+
+    /// Let subscribers know something happened to this server
+    fn publish(&self, kind: LifecycleEventKind) {
+        self.events.publish_lifecycle(LifecycleEvent {
+            uuid: self.uuid,
+            name: self.name.clone(),
+            ctrl_channel_id: self.ctrl_channel_id.clone(),
+            certificate_fingerprint: self.certificate_fingerprint.clone(),
+            kind,
+        });
+    }
+
+    /// Kill the client, publishing an offline event if it had come online
+    fn offline(&mut self, reason: String) {
+        self.kill();
+        if !self.name.is_empty() && !self.ctrl_channel_id.is_empty() {
+            self.publish(LifecycleEventKind::Offline { reason });
+        }
+    }
+
+    /// Whether the plugin will reply to `ServerRun` packets with a `CommandResponse`
+    fn sends_command_responses(&self) -> bool {
+        self.protocol_version >= 2 && self.capabilities.contains(CAPABILITY_COMMAND_RESPONSE)
+    }
+
+    /// Check the plugin's answer to the auth challenge, disconnecting it if it is wrong
+    async fn authenticate(&mut self, response: String) {
+        if self.authenticated {
+            debug!("{} tried to authenticate twice", self.uuid);
+            return;
+        }
+
+        if self.authenticator.verify(&self.challenge, &response) {
+            info!("Authenticated {}", self.uuid);
+            self.authenticated = true;
+            self.outgoing_stream
+                .send(OutgoingPacket::Authenticated)
+                .unwrap_or(());
+        } else {
+            info!("Failed authentication for {}", self.uuid);
+            self.outgoing_stream
+                .send(OutgoingPacket::Error(
+                    ErrorType::Unauthorized,
+                    "Invalid challenge response".to_string(),
+                ))
+                .unwrap_or(());
+            self.kill();
+        }
+    }
+
+    /// Relay something a player said to the bridge channel
+    async fn handle_chat_message(&mut self, message: ChatMessagePacket) {
+        if self.bridge_channel_id.is_empty() {
+            self.outgoing_stream
+                .send(OutgoingPacket::Error(
+                    ErrorType::NoBridgeChannel,
+                    "Set a bridge channel before sending chat messages".to_string(),
+                ))
+                .unwrap_or(());
+            return;
+        }
+
+        self.events.publish_chat(ChatEvent {
+            uuid: self.uuid,
+            name: self.name.clone(),
+            bridge_channel_id: self.bridge_channel_id.clone(),
+            player: message.player,
+            text: message.text,
+            avatar: message.avatar,
+        });
+    }
+
+    /// Post the response to a `ServerRun` packet in the channel the command came from
+    async fn handle_command_response(&mut self, response: CommandResponsePacket) {
+        let pending = match self.pending.remove(&response.request_id) {
+            Some(pending) => pending,
+            None => {
+                debug!(
+                    "Received response to unknown request {} from {}",
+                    response.request_id, self.name
+                );
+                self.outgoing_stream
+                    .send(OutgoingPacket::Error(
+                        ErrorType::UnknownRequestID,
+                        format!("Unknown request ID {}", response.request_id),
+                    ))
+                    .unwrap_or(());
+                return;
+            }
+        };
+        self.metrics.command_responded(pending.sent_at.elapsed());
+
+        self.audit
+            .record(AuditRecord::Response(ResponseRecord {
+                timestamp: audit::now(),
+                request_id: response.request_id,
+                server: AuditServer {
+                    uuid: self.uuid,
+                    name: self.name.clone(),
+                },
+                run: response.run.clone(),
+                query: response.query.clone(),
+                set: response.set.clone(),
+            }))
+            .await;
+
+        let embed = match create_embed(
+            "Command response",
+            Some(&self.name),
+            response_fields(&pending.command, response),
+        ) {
+            Ok(embed) => embed,
+            Err(err) => {
+                error!("Error building response embed for {}, {}", self.name, err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.platform.send_embed(pending.channel_id, embed).await {
+            error!(
+                "Error sending response from {} to discord, {}",
+                self.name, err
+            );
         }
     }
 
+    /// Send a command to the server, the response will be posted to `channel_id`
     pub async fn send_server_command(
-        &self,
+        &mut self,
+        request_id: Uuid,
+        channel_id: ChannelId,
         exec: ServerCommand,
     ) -> Result<(), SendError<OutgoingPacket>> {
+        self.pending
+            .retain(|_, pending| pending.sent_at.elapsed() < PENDING_REQUEST_TIMEOUT);
+        if self.sends_command_responses() {
+            self.pending.insert(
+                request_id,
+                PendingRequest {
+                    channel_id,
+                    command: exec.clone(),
+                    sent_at: Instant::now(),
+                },
+            );
+        }
+
+        let result = self
+            .outgoing_stream
+            .send(OutgoingPacket::ServerRun(request_id, exec.exec()));
+        self.metrics.command_sent(result.is_ok());
+        if result.is_err() {
+            self.pending.remove(&request_id);
+        }
+        result
+    }
+
+    /// Relay a message from the bridge channel into game chat, plugins that don't support chat
+    /// are skipped
+    pub async fn send_chat_message(
+        &self,
+        author: String,
+        text: String,
+    ) -> Result<(), SendError<OutgoingPacket>> {
+        if !self.capabilities.contains(CAPABILITY_CHAT) {
+            return Ok(());
+        }
         self.outgoing_stream
-            .send(OutgoingPacket::ServerRun(exec))
-            .await
+            .send(OutgoingPacket::ChatMessage(author, text))
+    }
+
+    /// Close the connection, without letting the server resume its session
+    pub fn kick(&mut self) {
+        self.kicked = true;
+        self.disconnect.notify_one();
+    }
+
+    /// Close the connection because the bridge is shutting down, telling the plugin when to
+    /// reconnect
+    pub fn going_away(&mut self, reconnect_after: Duration) {
+        self.going_away = Some(reconnect_after);
+        self.disconnect.notify_one();
+    }
+
+    /// Why `disconnect` fired, and the packet telling the plugin if its protocol version has one
+    fn disconnect_reason(&self) -> (Option<OutgoingPacket>, &'static str) {
+        if let Some(reconnect_after) = self.going_away {
+            // Plugins from before GoingAway only see the socket close
+            let packet = (self.protocol_version >= 3)
+                .then_some(OutgoingPacket::GoingAway(reconnect_after.as_secs()));
+            return (packet, "Bridge shutting down");
+        }
+        let (error_type, reason) = if self.kicked {
+            (ErrorType::Kicked, "Kicked by an administrator")
+        } else {
+            (ErrorType::NameConflict, "Replaced by a new connection")
+        };
+        (
+            Some(OutgoingPacket::Error(error_type, reason.to_string())),
+            reason,
+        )
+    }
+
+    /// Everything known about the server, for the admin API
+    pub fn details(&self) -> ServerDetails {
+        let mut capabilities = self.capabilities.iter().cloned().collect::<Vec<String>>();
+        capabilities.sort();
+        ServerDetails {
+            info: self.info(),
+            authenticated: self.authenticated,
+            protocol_version: self.protocol_version,
+            capabilities,
+            pending_requests: self.pending.len(),
+            resumable: self.resume_token.is_some(),
+        }
     }
 
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
 
+    /// Whether the connection was lost and the server may still resume its session
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
     pub fn kill(&mut self) {
         info!("Stopping {}", self.uuid);
         self.alive = false;
+        self.registry.release(self.connection_id);
+        self.directory.remove(self.connection_id);
     }
 }
+
+/// Counts a connection as live until its main loop ends
+struct LiveConnection(Arc<AtomicUsize>);
+
+impl LiveConnection {
+    fn new(live: Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, Ordering::SeqCst);
+        Self(live)
+    }
+}
+
+impl Drop for LiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Count a packet as it goes out on the wire
+fn count_sent(metrics: &Metrics, packet: &OutgoingPacket) {
+    metrics.packet_sent(packet.name());
+    if let OutgoingPacket::Error(error_type, _) = packet {
+        metrics.packet_error(&format!("{:?}", error_type));
+    }
+}
+
+/// Serialize a packet, along with its sequence number if it has one
+fn serialize(packet: &OutgoingPacket, version: u32, seq: Option<u64>) -> String {
+    match seq {
+        Some(seq) => {
+            let mut value = serde_json::to_value(packet.versioned(version)).unwrap();
+            value["seq"] = seq.into();
+            value.to_string()
+        }
+        None => serde_json::to_string(&packet.versioned(version)).unwrap(),
+    }
+}
+
+/// Build the embed fields for a response, one field per `run`, `query` and `set` entry
+fn response_fields(command: &ServerCommand, response: CommandResponsePacket) -> Vec<EmbedField> {
+    let mut fields = vec![];
+
+    for (i, output) in response.run.into_iter().enumerate() {
+        let name = match command.run.get(i) {
+            Some(run) => format!("Run `{}`", run),
+            None => format!("Run #{}", i + 1),
+        };
+        fields.push(EmbedFieldBuilder::new(name, field_value(output)).build());
+    }
+
+    for (key, value) in response.query {
+        fields.push(
+            EmbedFieldBuilder::new(format!("Query `{}`", key), field_value(value))
+                .inline()
+                .build(),
+        );
+    }
+
+    for (key, success) in response.set {
+        let value = match command.set.get(&key) {
+            Some(value) if success => format!(":white_check_mark: {}", value),
+            Some(value) => format!(":x: {}", value),
+            None if success => ":white_check_mark:".to_string(),
+            None => ":x:".to_string(),
+        };
+        fields.push(
+            EmbedFieldBuilder::new(format!("Set `{}`", key), value)
+                .inline()
+                .build(),
+        );
+    }
+
+    fields.truncate(MAX_EMBED_FIELDS);
+    fields
+}
@@ -0,0 +1,156 @@
+//! What is known about every connected server, readable without locking any client
+use crate::ws::{client::WsClient, selector::Selector, Am};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use uuid::Uuid;
+
+/// A server's metadata, kept up to date by its client
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub uuid: Uuid,
+    pub name: String,
+    pub ctrl_channel_id: String,
+    pub bridge_channel_id: String,
+    /// Key/value pairs selectors can target, such as `region=eu`
+    pub tags: BTreeMap<String, String>,
+    /// Hex encoded SHA-256 fingerprint of the TLS client certificate the plugin connected with
+    pub certificate_fingerprint: Option<String>,
+}
+
+struct Entry {
+    info: ServerInfo,
+    client: Am<WsClient>,
+}
+
+/// Every client by connection id, with indexes by control channel and by name. Updates borrow an
+/// entry while reindexing it, lookups copy ids out of an index before borrowing entries, so an
+/// index is never borrowed while waiting for an entry and lookups can't deadlock against updates
+#[derive(Default)]
+pub(super) struct Directory {
+    entries: DashMap<Uuid, Entry>,
+    /// Connection ids of the servers in each control channel
+    by_ctrl_channel: DashMap<String, HashSet<Uuid>>,
+    /// Connection id of each server by control channel and name
+    by_name: DashMap<(String, String), Uuid>,
+}
+
+impl Directory {
+    pub fn insert(&self, connection_id: Uuid, info: ServerInfo, client: Am<WsClient>) {
+        self.entries.insert(connection_id, Entry { info, client });
+    }
+
+    /// Replace a client's metadata. The entry stays borrowed until it is reindexed, so concurrent
+    /// updates of one entry can't leave stale index entries behind
+    pub fn update(&self, connection_id: Uuid, info: ServerInfo) {
+        let mut entry = match self.entries.get_mut(&connection_id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.info == info {
+            return;
+        }
+        self.unindex(connection_id, &entry.info);
+        self.index(connection_id, &info);
+        entry.info = info;
+    }
+
+    pub fn remove(&self, connection_id: Uuid) {
+        if let Some((_, entry)) = self.entries.remove(&connection_id) {
+            self.unindex(connection_id, &entry.info);
+        }
+    }
+
+    fn index(&self, connection_id: Uuid, info: &ServerInfo) {
+        if info.ctrl_channel_id.is_empty() {
+            return;
+        }
+        self.by_ctrl_channel
+            .entry(info.ctrl_channel_id.clone())
+            .or_default()
+            .insert(connection_id);
+        if !info.name.is_empty() {
+            self.by_name.insert(
+                (info.ctrl_channel_id.clone(), info.name.clone()),
+                connection_id,
+            );
+        }
+    }
+
+    fn unindex(&self, connection_id: Uuid, info: &ServerInfo) {
+        if let Some(mut ids) = self.by_ctrl_channel.get_mut(&info.ctrl_channel_id) {
+            ids.remove(&connection_id);
+        }
+        self.by_ctrl_channel
+            .remove_if(&info.ctrl_channel_id, |_, ids| ids.is_empty());
+        self.by_name.remove_if(
+            &(info.ctrl_channel_id.clone(), info.name.clone()),
+            |_, id| *id == connection_id,
+        );
+    }
+
+    fn get(&self, connection_id: &Uuid) -> Option<(ServerInfo, Am<WsClient>)> {
+        self.entries
+            .get(connection_id)
+            .map(|entry| (entry.info.clone(), entry.client.clone()))
+    }
+
+    /// Every server, registered or not
+    pub fn all(&self) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.info.clone(), entry.client.clone()))
+            .collect()
+    }
+
+    pub fn by_uuid(&self, uuid: Uuid) -> Option<(ServerInfo, Am<WsClient>)> {
+        self.entries
+            .iter()
+            .find(|entry| entry.info.uuid == uuid)
+            .map(|entry| (entry.info.clone(), entry.client.clone()))
+    }
+
+    /// Every server in `ctrl_channel_id`
+    pub fn by_ctrl_channel(&self, ctrl_channel_id: &str) -> Vec<(ServerInfo, Am<WsClient>)> {
+        // Copied out so the index isn't borrowed while reading entries
+        let ids = self
+            .by_ctrl_channel
+            .get(ctrl_channel_id)
+            .map(|ids| ids.clone())
+            .unwrap_or_default();
+        ids.iter().filter_map(|id| self.get(id)).collect()
+    }
+
+    /// The server named `name` in `ctrl_channel_id`
+    pub fn by_name(&self, ctrl_channel_id: &str, name: &str) -> Option<(ServerInfo, Am<WsClient>)> {
+        let id = *self
+            .by_name
+            .get(&(ctrl_channel_id.to_string(), name.to_string()))?;
+        self.get(&id)
+    }
+
+    /// Every server in `ctrl_channel_id` that `selector` matches
+    pub fn by_selector(
+        &self,
+        ctrl_channel_id: &str,
+        selector: &Selector,
+    ) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.by_ctrl_channel(ctrl_channel_id)
+            .into_iter()
+            .filter(|(info, _)| selector.matches(info))
+            .collect()
+    }
+
+    /// Every server bridged with `bridge_channel_id`
+    pub fn by_bridge_channel(&self, bridge_channel_id: &str) -> Vec<(ServerInfo, Am<WsClient>)> {
+        if bridge_channel_id.is_empty() {
+            return vec![];
+        }
+        self.entries
+            .iter()
+            .filter(|entry| entry.info.bridge_channel_id == bridge_channel_id)
+            .map(|entry| (entry.info.clone(), entry.client.clone()))
+            .collect()
+    }
+}
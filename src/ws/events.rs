@@ -0,0 +1,130 @@
+//! Events for connected servers, published to anything that subscribes through
+//! [`WsManager`](super::WsManager)
+use super::packets::ServerEventKind;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleEvent {
+    pub uuid: Uuid,
+    pub name: String,
+    pub ctrl_channel_id: String,
+    /// Fingerprint of the TLS client certificate the plugin connected with
+    pub certificate_fingerprint: Option<String>,
+    #[serde(flatten)]
+    pub kind: LifecycleEventKind,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum LifecycleEventKind {
+    /// The server has both a name and a control channel
+    Online,
+    Offline {
+        reason: String,
+    },
+    Renamed {
+        old_name: String,
+    },
+    ControlChannelChanged {
+        old_ctrl_channel_id: String,
+    },
+}
+
+/// Something a player said in game, to be relayed to the server's bridge channel
+#[derive(Clone, Debug)]
+pub struct ChatEvent {
+    pub uuid: Uuid,
+    pub name: String,
+    pub bridge_channel_id: String,
+    pub player: String,
+    pub text: String,
+    /// URL of the player's avatar
+    pub avatar: Option<String>,
+}
+
+/// Something a plugin reported happening on its server
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerEvent {
+    pub uuid: Uuid,
+    pub name: String,
+    pub ctrl_channel_id: String,
+    #[serde(flatten)]
+    pub kind: ServerEventKind,
+}
+
+/// The kinds of [`ServerEvent`], without their fields, used to pick which ones get posted
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ServerEventType {
+    PlayerJoined,
+    PlayerLeft,
+    PlayerDied,
+    PlayerKicked,
+    Starting,
+    Stopping,
+    Crashed,
+}
+
+impl ServerEventType {
+    pub fn of(kind: &ServerEventKind) -> Self {
+        match kind {
+            ServerEventKind::PlayerJoined { .. } => ServerEventType::PlayerJoined,
+            ServerEventKind::PlayerLeft { .. } => ServerEventType::PlayerLeft,
+            ServerEventKind::PlayerDied { .. } => ServerEventType::PlayerDied,
+            ServerEventKind::PlayerKicked { .. } => ServerEventType::PlayerKicked,
+            ServerEventKind::Starting => ServerEventType::Starting,
+            ServerEventKind::Stopping => ServerEventType::Stopping,
+            ServerEventKind::Crashed { .. } => ServerEventType::Crashed,
+        }
+    }
+}
+
+/// The channels every client publishes its events on
+#[derive(Clone)]
+pub struct EventBus {
+    lifecycle: broadcast::Sender<LifecycleEvent>,
+    chat: broadcast::Sender<ChatEvent>,
+    server: broadcast::Sender<ServerEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        // Subscribers that fall this far behind start missing events
+        Self {
+            lifecycle: broadcast::channel(64).0,
+            chat: broadcast::channel(256).0,
+            server: broadcast::channel(256).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish_lifecycle(&self, event: LifecycleEvent) {
+        // Nobody listening is not an error
+        let _ = self.lifecycle.send(event);
+    }
+
+    pub fn publish_chat(&self, event: ChatEvent) {
+        let _ = self.chat.send(event);
+    }
+
+    pub fn publish_server(&self, event: ServerEvent) {
+        let _ = self.server.send(event);
+    }
+
+    pub fn subscribe_lifecycle(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.lifecycle.subscribe()
+    }
+
+    pub fn subscribe_chat(&self) -> broadcast::Receiver<ChatEvent> {
+        self.chat.subscribe()
+    }
+
+    pub fn subscribe_server(&self) -> broadcast::Receiver<ServerEvent> {
+        self.server.subscribe()
+    }
+}
@@ -1,25 +1,143 @@
+mod auth;
 mod client;
+mod directory;
+mod events;
 mod packets;
+mod registry;
+mod selector;
+mod tls;
 
-use crate::ws::client::WsClient;
+pub use client::{ServerDetails, WsClient};
+pub use directory::ServerInfo;
+pub use events::{
+    ChatEvent, EventBus, LifecycleEvent, LifecycleEventKind, ServerEvent, ServerEventType,
+};
+pub use packets::ServerEventKind;
+pub use registry::NameConflictPolicy;
+pub use selector::{Pattern, Selector, SelectorError};
+pub use tls::{TlsConfig, TlsError};
+
+use crate::{
+    audit::AuditLog,
+    config::{self, Config},
+    discord::platform::ChatPlatform,
+    metrics::Metrics,
+    ws::{auth::Authenticator, directory::Directory, registry::Registry},
+};
+use serde::Deserialize;
 use log:: info;
-use regex::Regex;
+use tokio::sync::broadcast;
+use std::future::Future;
+use std::net::SocketAddr;
 use std::time::Duration;
-use std::{collections::HashMap, env, sync::Arc};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{Mutex, Notify},
 };
-use twilight_http::client::Client as HttpClient;
 use uuid::Uuid;
 
 pub struct WsManager {
-    connections: Am<HashMap<Uuid, Am<WsClient>>>,
-    get_http: Am<Option<Arc<HttpClient>>>,
+    directory: Arc<Directory>,
+    platform: Am<Option<Arc<dyn ChatPlatform>>>,
+    local_addr: SocketAddr,
+    events: EventBus,
+    /// Stops the accept loop
+    stop_accepting: Arc<Notify>,
+    /// How many connections are still running, including suspended ones
+    live: Arc<AtomicUsize>,
+    shutdown: ShutdownConfig,
 }
 
 pub type Am<T> = Arc<Mutex<T>>;
 
+/// A plugin's connection, plain TCP or TLS
+pub trait PluginStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PluginStream for T {}
+
+/// State every client shares
+#[derive(Clone)]
+struct Shared {
+    directory: Arc<Directory>,
+    authenticator: Arc<Authenticator>,
+    heartbeat: HeartbeatConfig,
+    resume: ResumeConfig,
+    audit: Arc<AuditLog>,
+    events: EventBus,
+    registry: Arc<Registry>,
+    metrics: Arc<Metrics>,
+    live: Arc<AtomicUsize>,
+}
+
+/// How often plugins are pinged, and how many unanswered pings mean a plugin is gone
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+pub struct HeartbeatConfig {
+    /// In seconds
+    #[serde(deserialize_with = "config::seconds")]
+    pub interval: Duration,
+    pub missed_beats: u32,
+}
+
+impl HeartbeatConfig {
+    /// How long a plugin can go without sending anything before it is considered dead
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.missed_beats
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            missed_beats: 3,
+        }
+    }
+}
+
+/// How long a disconnected plugin has to resume its session
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+pub struct ResumeConfig {
+    /// In seconds
+    #[serde(deserialize_with = "config::seconds")]
+    pub grace_period: Duration,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How the bridge lets go of its plugins when it shuts down
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields, default)]
+pub struct ShutdownConfig {
+    /// Seconds plugins are told to wait before reconnecting
+    #[serde(deserialize_with = "config::seconds")]
+    pub reconnect_after: Duration,
+    /// Seconds to wait for connections to flush their queues and close
+    #[serde(deserialize_with = "config::seconds")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_after: Duration::from_secs(5),
+            drain_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! am {
     ($a:expr) => {
@@ -28,133 +146,197 @@ macro_rules! am {
 }
 
 impl WsManager {
-    pub async fn new() -> Self {
-        let addr = env::args()
-            .nth(1)
-            .unwrap_or_else(|| "127.0.0.1:8080".to_string());
-
+    pub async fn new(config: &Config, audit: Arc<AuditLog>, metrics: Arc<Metrics>) -> Self {
         // Create the event loop and TCP listener we'll accept connections on.
-        let try_socket = TcpListener::bind(&addr).await;
+        let try_socket = TcpListener::bind(&config.bind).await;
         let listener = try_socket.expect("Failed to bind");
+        let local_addr = listener.local_addr().expect("Failed to bind");
+
+        let tls = config.tls.clone().map(|tls| {
+            tls.watch()
+                .unwrap_or_else(|err| panic!("Failed to load TLS certificate, {}", err))
+        });
+        let events = EventBus::default();
 
-        let connections = am!(HashMap::new());
-        let connections2 = connections.clone();
-        let get_discord: Arc<Mutex<Option<Arc<HttpClient>>>> = am!(None);
-        let get_discord2 = get_discord.clone();
+        let directory = Arc::new(Directory::default());
+        let live = Arc::new(AtomicUsize::new(0));
+        let shared = Shared {
+            directory: directory.clone(),
+            authenticator: Arc::new(Authenticator::new(config.plugins.secret.expose())),
+            heartbeat: config.heartbeat,
+            resume: config.resume,
+            audit,
+            events: events.clone(),
+            registry: Arc::new(Registry::new(config.name_conflict)),
+            metrics,
+            live: live.clone(),
+        };
+        let platform: Am<Option<Arc<dyn ChatPlatform>>> = am!(None);
+        let platform2 = platform.clone();
+        let stop_accepting = Arc::new(Notify::new());
+        let stop_accepting2 = stop_accepting.clone();
 
         tokio::spawn(async move {
             loop {
-                let k = get_discord2.clone();
+                let k = platform2.clone();
                 let lock = k.lock().await;
                 if lock.is_some() {
                     break;
                 }
                 tokio::time::sleep(Duration::from_secs_f32(0.5)).await;
             }
-            while let Ok((stream, addr)) = listener.accept().await {
-                let connections3 = connections2.clone();
-                let get_http = get_discord2.clone();
-                let locked = get_http.lock().await;
-                Self::handle_stream(connections3, (*locked).as_ref().unwrap().clone(), stream);
-                info!("New connection from {}", addr);
-            }
-        });
-
-        let connections2 = connections.clone();
-
-        tokio::spawn(async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(10)).await;
-                let mut connections = connections2.lock().await;
-                for (id, _) in connections.clone().iter().filter(|(_, client)| {
-                    futures::executor::block_on(async { !client.lock().await.alive })
-                }) {
-                    connections.remove(id);
-                    info!("Removing dead client {}", id);
-                }
-                drop(connections);
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(_) => break,
+                    },
+                    _ = stop_accepting2.notified() => {
+                        info!("No longer accepting connections");
+                        break;
+                    }
+                };
+                let platform = (*platform2.lock().await).as_ref().unwrap().clone();
+                let acceptor = tls.as_ref().map(|tls| tls.borrow().clone());
+                let shared = shared.clone();
+                // The TLS handshake happens off the accept loop, so slow plugins don't hold up others
+                tokio::spawn(async move {
+                    if let Some((stream, fingerprint)) = tls::accept(stream, acceptor, addr).await {
+                        Self::handle_stream(shared, platform, stream, fingerprint);
+                        info!("New connection from {}", addr);
+                    }
+                });
             }
         });
 
         Self {
-            connections,
-            get_http: get_discord,
+            directory,
+            platform,
+            local_addr,
+            events,
+            stop_accepting,
+            live,
+            shutdown: config.shutdown,
         }
     }
 
     fn handle_stream(
-        connections: Am<HashMap<Uuid, Am<WsClient>>>,
-        http: Arc<HttpClient>,
-        stream: TcpStream,
+        shared: Shared,
+        platform: Arc<dyn ChatPlatform>,
+        stream: Box<dyn PluginStream>,
+        certificate_fingerprint: Option<String>,
     ) {
         tokio::spawn(async move {
-            let new_uuid = Uuid::new_v4();
-            connections.lock().await.insert(
-                new_uuid.clone(),
-                WsClient::new(new_uuid, http, stream).await,
-            );
+            // Clients add themselves to the directory, and remove themselves once they are gone
+            WsClient::new(
+                Uuid::new_v4(),
+                platform,
+                shared,
+                stream,
+                certificate_fingerprint,
+            )
+            .await;
         });
     }
 
-    pub async fn get_connected_by_ctrl_channel_id(&self, ctrl_channel_id: String) -> Vec<(Uuid, Am<WsClient>)> {
-        self.connections
-            .lock()
-            .await
-            .iter()
-            .filter(|(_, connection)| {
-                futures::executor::block_on(async {
-                    &connection.lock().await.ctrl_channel_id == &ctrl_channel_id
-                })
-            })
-            .map(|(e, connection)| (e.to_owned(), connection.to_owned()))
-            .collect::<Vec<(Uuid, Am<WsClient>)>>()
+    /// Every connected server
+    pub fn get_connected(&self) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.directory.all()
+    }
+
+    pub fn get_connection_by_uuid(&self, uuid: Uuid) -> Option<(ServerInfo, Am<WsClient>)> {
+        self.directory.by_uuid(uuid)
     }
-    pub async fn get_connections_by_regex(
+
+    /// Every server controlled from `ctrl_channel_id`
+    pub fn get_connected_by_ctrl_channel_id(
         &self,
-        regex: Regex,
-        ctrl_channel_id: String,
-    ) -> Vec<(Uuid, Am<WsClient>)> {
-        self
-            .connections
-            .lock()
-            .await
-            .iter()
-            .filter(|(_, connection)| {
-                futures::executor::block_on(async {
-                    let lock = connection.lock().await;
-                    let a = &lock.ctrl_channel_id == &ctrl_channel_id;
-                    let b = regex.is_match(&lock.name);
-                    a && b
-                })
-            })
-            .map(|(a, b)| (a.to_owned(), b.to_owned()))
-            .collect::<Vec<(Uuid, Am<WsClient>)>>()
+        ctrl_channel_id: &str,
+    ) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.directory.by_ctrl_channel(ctrl_channel_id)
     }
 
-    pub async fn get_connection_by_name(
+    /// Every server controlled from `ctrl_channel_id` that `selector` matches
+    pub fn get_connections_by_selector(
         &self,
-        name: String,
-        ctrl_channel_id: String,
-    ) -> Option<(Uuid, Am<WsClient>)> {
-        match self
-            .connections
-            .lock()
-            .await
-            .iter()
-            .filter(|(_, connection)| {
-                futures::executor::block_on(async {
-                    let lock = connection.lock().await;
-                    &lock.ctrl_channel_id == &ctrl_channel_id && &lock.name == &name
-                })
+        selector: &Selector,
+        ctrl_channel_id: &str,
+    ) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.directory.by_selector(ctrl_channel_id, selector)
+    }
+
+    /// The server named `name` in `ctrl_channel_id`
+    pub fn get_connection_by_name(
+        &self,
+        name: &str,
+        ctrl_channel_id: &str,
+    ) -> Option<(ServerInfo, Am<WsClient>)> {
+        self.directory.by_name(ctrl_channel_id, name)
+    }
+
+    /// Every connected server whose chat is bridged with `bridge_channel_id`
+    pub fn get_connected_by_bridge_channel_id(
+        &self,
+        bridge_channel_id: &str,
+    ) -> Vec<(ServerInfo, Am<WsClient>)> {
+        self.directory.by_bridge_channel(bridge_channel_id)
+    }
+
+    /// Receive every lifecycle event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.events.subscribe_lifecycle()
+    }
+
+    /// Receive every chat message published from now on
+    pub fn subscribe_chat(&self) -> broadcast::Receiver<ChatEvent> {
+        self.events.subscribe_chat()
+    }
+
+    /// Receive every server event published from now on
+    pub fn subscribe_server_events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe_server()
+    }
+
+    /// Stop accepting plugins, tell every connected one to come back once the bridge is up again,
+    /// and wait for their queues to be flushed. Resolves to whether every connection closed in
+    /// time. The future doesn't borrow the manager, so it can be awaited without holding its lock
+    pub fn shutdown(&self) -> impl Future<Output = bool> + Send + 'static {
+        let (directory, live, stop_accepting) = (
+            self.directory.clone(),
+            self.live.clone(),
+            self.stop_accepting.clone(),
+        );
+        let shutdown = self.shutdown;
+        async move {
+            stop_accepting.notify_one();
+            for (_, client) in directory.all() {
+                client.lock().await.going_away(shutdown.reconnect_after);
+            }
+
+            let drained = tokio::time::timeout(shutdown.drain_timeout, async {
+                while live.load(Ordering::SeqCst) > 0 {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
             })
-            .next()
-        {
-            Some((a, b)) => Some((a.to_owned(), b.to_owned())),
-            None => None,
+            .await
+            .is_ok();
+            if !drained {
+                info!(
+                    "{} connections did not close in time",
+                    live.load(Ordering::SeqCst)
+                );
+            }
+            drained
         }
     }
 
-    pub async fn set_get_http(&mut self, fun: Arc<HttpClient>) {
-        *self.get_http.lock().await = Some(fun);
+    /// Where plugins' responses are posted, connections are only accepted once this is set
+    pub async fn set_platform(&mut self, platform: Arc<dyn ChatPlatform>) {
+        *self.platform.lock().await = Some(platform);
+    }
+
+    /// Address the plugin listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
     }
 }
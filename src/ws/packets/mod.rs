@@ -1,6 +1,3 @@
-//! All of the serialization/deserialization code for packets sent and received over websockets
-mod incoming;
-mod outgoing;
-
-pub use incoming::IncomingPacket;
-pub use outgoing::*;
+//! Packets sent and received over websockets, shared with the client SDK through the
+//! `tc-discord-protocol` crate
+pub use tc_discord_protocol::*;
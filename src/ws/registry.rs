@@ -0,0 +1,202 @@
+//! Which connection holds each server name in a control channel, which server each stable
+//! server id belongs to, and which sessions can be resumed
+use crate::ws::{client::WsClient, Am};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// What happens when a server asks for a name another server in the same control channel has
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum NameConflictPolicy {
+    /// The new server keeps its previous name
+    #[default]
+    Reject,
+    /// The new server gets the first free `<name>-<n>`
+    Suffix,
+    /// The old server is disconnected
+    Replace,
+}
+
+/// The outcome of asking for a name
+pub(super) enum Claim {
+    Granted,
+    Rejected,
+    /// Granted under a different name
+    Suffixed(String),
+    /// Granted, the previous holder has been told to disconnect
+    Replaced,
+}
+
+/// Connections are identified by the id they were given when they connected, which unlike their
+/// uuid never changes
+pub(super) struct Registry {
+    policy: NameConflictPolicy,
+    // Never held across an await
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Notified when the connection has been replaced and should disconnect
+    sessions: HashMap<Uuid, Arc<Notify>>,
+    /// Connection holding each (control channel id, name)
+    names: HashMap<(String, String), Uuid>,
+    /// Uuid and connection of each stable server id, kept after disconnecting so the server gets
+    /// the same uuid back when it reconnects
+    server_ids: HashMap<String, (Uuid, Uuid)>,
+    /// Disconnected clients waiting to be resumed, and their connection ids, by resume token
+    suspended: HashMap<String, (Uuid, Am<WsClient>)>,
+}
+
+impl Inner {
+    fn replace(&mut self, connection_id: Uuid) {
+        if let Some(session) = self.sessions.get(&connection_id) {
+            session.notify_one();
+        }
+        self.names.retain(|_, holder| *holder != connection_id);
+    }
+
+    /// Hand everything `from` holds over to `to`
+    fn transfer(&mut self, from: Uuid, to: Uuid) {
+        for holder in self.names.values_mut() {
+            if *holder == from {
+                *holder = to;
+            }
+        }
+        for (_, connection_id) in self.server_ids.values_mut() {
+            if *connection_id == from {
+                *connection_id = to;
+            }
+        }
+    }
+
+    fn take(&mut self, connection_id: Uuid, key: (String, String)) {
+        self.names.retain(|_, holder| *holder != connection_id);
+        self.names.insert(key, connection_id);
+    }
+}
+
+impl Registry {
+    pub fn new(policy: NameConflictPolicy) -> Self {
+        Self {
+            policy,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Start tracking a connection, the returned `Notify` fires when it should disconnect
+    pub fn register(&self, connection_id: Uuid) -> Arc<Notify> {
+        let session = Arc::new(Notify::new());
+        self.inner
+            .lock()
+            .unwrap()
+            .sessions
+            .insert(connection_id, session.clone());
+        session
+    }
+
+    /// Forget a connection that has gone away, freeing its name
+    pub fn release(&self, connection_id: Uuid) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sessions.remove(&connection_id);
+        inner.names.retain(|_, holder| *holder != connection_id);
+    }
+
+    /// Ask for `name` in `ctrl_channel_id`, giving up any name the connection held before unless
+    /// the claim is rejected
+    pub fn claim(&self, connection_id: Uuid, ctrl_channel_id: &str, name: &str) -> Claim {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (ctrl_channel_id.to_string(), name.to_string());
+        let holder = match inner.names.get(&key) {
+            Some(holder) if *holder != connection_id => *holder,
+            _ => {
+                inner.take(connection_id, key);
+                return Claim::Granted;
+            }
+        };
+
+        match self.policy {
+            NameConflictPolicy::Reject => Claim::Rejected,
+            NameConflictPolicy::Suffix => {
+                let suffixed = (2..)
+                    .map(|n| format!("{}-{}", name, n))
+                    .find(|suffixed| {
+                        match inner
+                            .names
+                            .get(&(ctrl_channel_id.to_string(), suffixed.clone()))
+                        {
+                            Some(holder) => *holder == connection_id,
+                            None => true,
+                        }
+                    })
+                    .unwrap();
+                inner.take(
+                    connection_id,
+                    (ctrl_channel_id.to_string(), suffixed.clone()),
+                );
+                Claim::Suffixed(suffixed)
+            }
+            NameConflictPolicy::Replace => {
+                inner.replace(holder);
+                inner.take(connection_id, key);
+                Claim::Replaced
+            }
+        }
+    }
+
+    /// Tie a connection to a stable server id, returning the uuid the server had before if it has
+    /// connected with this id already. A previous connection that is still around is replaced
+    pub fn reclaim(&self, server_id: String, connection_id: Uuid, uuid: Uuid) -> Option<Uuid> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.server_ids.get(&server_id).copied() {
+            Some((old_uuid, old_connection_id)) if old_connection_id != connection_id => {
+                inner.replace(old_connection_id);
+                inner
+                    .server_ids
+                    .insert(server_id, (old_uuid, connection_id));
+                Some(old_uuid)
+            }
+            Some(_) => None,
+            None => {
+                inner.server_ids.insert(server_id, (uuid, connection_id));
+                None
+            }
+        }
+    }
+
+    /// Keep a disconnected client around for `resume`, its name stays taken meanwhile
+    pub fn suspend(&self, resume_token: String, connection_id: Uuid, client: Am<WsClient>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .suspended
+            .insert(resume_token, (connection_id, client));
+    }
+
+    /// Hand a suspended client over to the connection resuming it. The suspended client is told
+    /// it has been replaced
+    pub fn resume(&self, resume_token: &str, connection_id: Uuid) -> Option<Am<WsClient>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (old_connection_id, client) = inner.suspended.remove(resume_token)?;
+        inner.transfer(old_connection_id, connection_id);
+        if let Some(session) = inner.sessions.get(&old_connection_id) {
+            session.notify_one();
+        }
+        Some(client)
+    }
+
+    /// Stop waiting for a suspended client to be resumed, returning whether it still was
+    pub fn expire(&self, resume_token: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .suspended
+            .remove(resume_token)
+            .is_some()
+    }
+}
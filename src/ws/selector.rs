@@ -0,0 +1,330 @@
+//! The language of the `on` field, which picks the servers a command is sent to
+//!
+//! ```text
+//! exact:lobby.eu                    the server named exactly lobby.eu
+//! glob:lobby-*                      names matching a glob, * and ? are wildcards
+//! regex:lobby-[0-9]+                names matching a regex, which has to match the whole name
+//!                                   and ends at whitespace or an unmatched )
+//! lobby-.*                          no terms, a regex over whole names, or an exact name if it
+//!                                   isn't a valid regex
+//! name:lobby-*                      names matching a glob, * and ? are wildcards
+//! name:/lobby-[0-9]+/               names matching a regex, which has to match the whole name
+//! name:lobby,survival               names matching any pattern in a list
+//! tag:region=eu                     servers tagged region=eu, values take the same patterns
+//! tag:region                        servers with any region tag
+//! tag:region=eu && !name:/test.*/   terms combined with &&, || and !, grouped with ( )
+//! ```
+//! `!` binds tighter than `&&`, which binds tighter than `||`. `exact:`, `glob:` and `regex:` are
+//! terms too, so `exact:lobby && tag:region=eu` combines them
+use crate::ws::ServerInfo;
+use regex::Regex;
+use thiserror::Error;
+
+/// Characters that end a name, tag or plain pattern
+const TERMINATORS: &[char] = &['&', '|', '(', ')', '!', ',', '='];
+/// Selectors starting a term with any of these are parsed as terms
+const TERM_PREFIXES: &[&str] = &["name:", "tag:", "exact:", "glob:", "regex:"];
+/// Characters a term can follow, besides whitespace
+const TERM_STARTS: &[char] = &['(', '!', '&', '|'];
+
+#[derive(Error, Debug)]
+#[error("{message} at column {column}")]
+pub struct SelectorError {
+    pub column: usize,
+    pub message: String,
+}
+
+/// How a name or tag value is matched
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Exact(String),
+    /// Compiled from a glob, anchored
+    Glob(Regex),
+    /// Anchored
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => exact == value,
+            Pattern::Glob(regex) | Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Selector {
+    /// The name matches any of the patterns
+    Name(Vec<Pattern>),
+    /// Tag `key` is set, to a value matching any of `values` unless there are none
+    Tag {
+        key: String,
+        values: Vec<Pattern>,
+    },
+    Not(Box<Selector>),
+    And(Box<Selector>, Box<Selector>),
+    Or(Box<Selector>, Box<Selector>),
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Self, SelectorError> {
+        // Selectors from before terms existed keep working, except that they have to match the
+        // whole name now
+        if !has_terms(source) {
+            return Ok(Selector::Name(vec![match anchored(source) {
+                Ok(regex) => Pattern::Regex(regex),
+                Err(_) => Pattern::Exact(source.to_string()),
+            }]));
+        }
+
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let selector = parser.or()?;
+        parser.skip_whitespace();
+        if parser.peek().is_some() {
+            return Err(parser.error("Expected && or ||"));
+        }
+        Ok(selector)
+    }
+
+    pub fn matches(&self, info: &ServerInfo) -> bool {
+        match self {
+            Selector::Name(patterns) => patterns.iter().any(|pattern| pattern.matches(&info.name)),
+            Selector::Tag { key, values } => match info.tags.get(key) {
+                Some(value) => {
+                    values.is_empty() || values.iter().any(|pattern| pattern.matches(value))
+                }
+                None => false,
+            },
+            Selector::Not(selector) => !selector.matches(info),
+            Selector::And(left, right) => left.matches(info) && right.matches(info),
+            Selector::Or(left, right) => left.matches(info) || right.matches(info),
+        }
+    }
+}
+
+/// Whether a term prefix starts `source`, or follows whitespace, ( or an operator in it. Plain
+/// names like `backup-tag:old` only contain one
+fn has_terms(source: &str) -> bool {
+    TERM_PREFIXES.iter().any(|prefix| {
+        source.match_indices(prefix).any(|(start, _)| {
+            source[..start]
+                .chars()
+                .next_back()
+                .is_none_or(|c| c.is_whitespace() || TERM_STARTS.contains(&c))
+        })
+    })
+}
+
+/// Recursive descent over the characters of a selector
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> SelectorError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: &str) -> SelectorError {
+        SelectorError {
+            column: pos + 1,
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `token` if it comes next, after any whitespace
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let found = token
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if found {
+            self.pos += token.chars().count();
+        }
+        found
+    }
+
+    /// Consume `c` if it comes next, whitespace is not allowed inside terms
+    fn eat_char(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Selector, SelectorError> {
+        let mut selector = self.and()?;
+        while self.eat("||") {
+            selector = Selector::Or(Box::new(selector), Box::new(self.and()?));
+        }
+        Ok(selector)
+    }
+
+    fn and(&mut self) -> Result<Selector, SelectorError> {
+        let mut selector = self.unary()?;
+        while self.eat("&&") {
+            selector = Selector::And(Box::new(selector), Box::new(self.unary()?));
+        }
+        Ok(selector)
+    }
+
+    fn unary(&mut self) -> Result<Selector, SelectorError> {
+        if self.eat("!") {
+            return Ok(Selector::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let selector = self.or()?;
+            if !self.eat(")") {
+                return Err(self.error("Expected )"));
+            }
+            return Ok(selector);
+        }
+        if self.eat("name:") {
+            return Ok(Selector::Name(self.patterns()?));
+        }
+        if self.eat("exact:") {
+            let name = self.word();
+            if name.is_empty() {
+                return Err(self.error("Expected a name"));
+            }
+            return Ok(Selector::Name(vec![Pattern::Exact(name)]));
+        }
+        if self.eat("glob:") {
+            let glob = self.word();
+            if glob.is_empty() {
+                return Err(self.error("Expected a glob"));
+            }
+            return Ok(Selector::Name(vec![glob_pattern(&glob)]));
+        }
+        if self.eat("regex:") {
+            return Ok(Selector::Name(vec![self.regex()?]));
+        }
+        if self.eat("tag:") {
+            let key = self.word();
+            if key.is_empty() {
+                return Err(self.error("Expected a tag name"));
+            }
+            let values = if self.eat_char('=') {
+                self.patterns()?
+            } else {
+                vec![]
+            };
+            return Ok(Selector::Tag { key, values });
+        }
+        Err(self.error("Expected name:, tag:, exact:, glob:, regex:, ! or ("))
+    }
+
+    /// A comma separated list of patterns
+    fn patterns(&mut self) -> Result<Vec<Pattern>, SelectorError> {
+        let mut patterns = vec![self.pattern()?];
+        while self.eat_char(',') {
+            patterns.push(self.pattern()?);
+        }
+        Ok(patterns)
+    }
+
+    /// `/regex/`, a glob or an exact value
+    fn pattern(&mut self) -> Result<Pattern, SelectorError> {
+        let start = self.pos;
+        if self.eat_char('/') {
+            let mut source = String::new();
+            loop {
+                match self.peek() {
+                    None => return Err(self.error_at(start, "Unterminated regex")),
+                    Some('/') => break,
+                    Some('\\') if self.chars.get(self.pos + 1) == Some(&'/') => {
+                        source.push('/');
+                        self.pos += 1;
+                    }
+                    Some(c) => source.push(c),
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            return anchored(&source)
+                .map(Pattern::Regex)
+                .map_err(|err| self.error_at(start, &format!("Invalid regex, {}", err)));
+        }
+
+        let word = self.word();
+        if word.is_empty() {
+            return Err(self.error("Expected a value"));
+        }
+        if !word.contains(['*', '?']) {
+            return Ok(Pattern::Exact(word));
+        }
+        Ok(glob_pattern(&word))
+    }
+
+    /// The value of `regex:`, up to whitespace or a `)` closing a group it didn't open
+    fn regex(&mut self) -> Result<Pattern, SelectorError> {
+        let start = self.pos;
+        let mut source = String::new();
+        let mut depth = 0usize;
+        while let Some(c) = self.peek() {
+            match c {
+                c if c.is_whitespace() => break,
+                ')' if depth == 0 => break,
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            source.push(c);
+            self.pos += 1;
+        }
+        if source.is_empty() {
+            return Err(self.error("Expected a regex"));
+        }
+        anchored(&source)
+            .map(Pattern::Regex)
+            .map_err(|err| self.error_at(start, &format!("Invalid regex, {}", err)))
+    }
+
+    /// Everything up to whitespace or a terminator
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || TERMINATORS.contains(&c) {
+                break;
+            }
+            word.push(c);
+            self.pos += 1;
+        }
+        word
+    }
+}
+
+/// A regex that has to match the whole value
+fn anchored(source: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", source))
+}
+
+/// `*` matches anything, `?` any one character, everything else itself
+fn glob_pattern(glob: &str) -> Pattern {
+    let source = glob
+        .chars()
+        .map(|c| match c {
+            '*' => ".*".to_string(),
+            '?' => ".".to_string(),
+            c => regex::escape(&c.to_string()),
+        })
+        .collect::<String>();
+    Pattern::Glob(anchored(&source).expect("Escaped globs are valid regexes"))
+}
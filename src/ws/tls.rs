@@ -0,0 +1,210 @@
+//! Optional TLS termination for the plugin listener, so plugins can connect over `wss://`
+use crate::{config, ws::PluginStream};
+use log::{error, info};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::{net::TcpStream, sync::watch};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore,
+        ServerConfig, Session, TLSError,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Could not read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No PKCS#8 or RSA private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Invalid client CA certificate in {0}")]
+    InvalidClientCa(PathBuf),
+    #[error("Invalid certificate or key: {0}")]
+    Rustls(#[from] TLSError),
+}
+
+/// Where the listener's certificate and key are, and how plugins must identify themselves
+/// ```yaml
+/// tls:
+///   cert: /etc/tc-discord/cert.pem
+///   key: /etc/tc-discord/key.pem
+///   clientCa: /etc/tc-discord/plugins-ca.pem
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM PKCS#8 or RSA private key
+    pub key: PathBuf,
+    /// PEM certificates plugin certificates must be signed by. When set, plugins without a valid
+    /// certificate are turned away
+    #[serde(default = "Default::default")]
+    pub client_ca: Option<PathBuf>,
+    /// Seconds between checks for changed certificate files
+    #[serde(
+        default = "default_reload_interval",
+        deserialize_with = "config::seconds"
+    )]
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert.as_path(), self.key.as_path()];
+        paths.extend(self.client_ca.as_deref());
+        paths
+    }
+
+    /// Build an acceptor from the files currently on disk
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let certs = read_pem(&self.cert, pemfile::certs)?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(self.cert.clone()));
+        }
+        let key = read_key(&self.key)?;
+
+        let mut server_config = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_pem(client_ca, pemfile::certs)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|_| TlsError::InvalidClientCa(client_ca.clone()))?;
+                }
+                if roots.is_empty() {
+                    return Err(TlsError::NoCertificates(client_ca.clone()));
+                }
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        server_config.set_single_cert(certs, key)?;
+
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+
+    /// Load the certificates, then keep reloading them whenever the files change. A reload that
+    /// fails keeps the previous certificates in use
+    pub fn watch(self) -> Result<watch::Receiver<TlsAcceptor>, TlsError> {
+        let (sender, receiver) = watch::channel(self.acceptor()?);
+
+        tokio::spawn(async move {
+            let mut last_modified = modified(&self.paths());
+            let mut interval = tokio::time::interval(self.reload_interval);
+            loop {
+                interval.tick().await;
+                let modified = modified(&self.paths());
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match self.acceptor() {
+                    Ok(acceptor) => {
+                        info!("Reloaded TLS certificate from {}", self.cert.display());
+                        if sender.send(acceptor).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => error!("Error reloading TLS certificate, {}", err),
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+}
+
+/// How long a plugin has to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Finish the TLS handshake with a new connection, if TLS is enabled. Comes with the fingerprint
+/// of the certificate the plugin identified itself with, if it sent one
+pub async fn accept(
+    stream: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+    addr: SocketAddr,
+) -> Option<(Box<dyn PluginStream>, Option<String>)> {
+    let acceptor = match acceptor {
+        Some(acceptor) => acceptor,
+        None => return Some((Box::new(stream), None)),
+    };
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            let fingerprint = client_fingerprint(&stream);
+            if let Some(fingerprint) = &fingerprint {
+                info!("{} identified by certificate {}", addr, fingerprint);
+            }
+            Some((Box::new(stream), fingerprint))
+        }
+        Ok(Err(err)) => {
+            info!("TLS handshake with {} failed, {}", addr, err);
+            None
+        }
+        Err(_) => {
+            info!("TLS handshake with {} timed out", addr);
+            None
+        }
+    }
+}
+
+/// Hex encoded SHA-256 fingerprint of the certificate a plugin connected with, if it sent one
+fn client_fingerprint(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    let leaf = certs.first()?;
+    Some(hex::encode(Sha256::digest(&leaf.0)))
+}
+
+fn read_pem<T>(
+    path: &Path,
+    parse: fn(&mut dyn io::BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<T>, TlsError> {
+    let file = File::open(path).map_err(|source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(&mut BufReader::new(file)).map_err(|_| TlsError::Read {
+        path: path.to_path_buf(),
+        source: io::Error::new(io::ErrorKind::InvalidData, "invalid PEM"),
+    })
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut keys = read_pem(path, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_pem(path, pemfile::rsa_private_keys)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+fn modified(paths: &[&Path]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(60)
+}
@@ -0,0 +1,92 @@
+//! The admin API, against a bridge with plugins connected
+mod common;
+
+use common::{http, Bridge, Plugin, ADMIN_TOKEN, CTRL_CHANNEL};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn requests_without_the_token_are_refused() {
+    let bridge = Bridge::start().await;
+    let addr = bridge.serve_admin();
+
+    for token in [None, Some("wrong token"), Some("admin toke")] {
+        let (status, _) = http(addr, "GET", "/servers", token, None).await;
+        assert_eq!(status, 401, "{:?}", token);
+    }
+    let (status, _) = http(addr, "GET", "/servers", Some(ADMIN_TOKEN), None).await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn servers_are_listed_with_their_details() {
+    let bridge = Bridge::start().await;
+    let addr = bridge.serve_admin();
+    let _plugin = Plugin::register(&bridge, "lobby", Some(json!({ "region": "eu" }))).await;
+    let uuid = bridge.server_uuid("lobby").await.unwrap();
+
+    let (status, body) = http(addr, "GET", "/servers", Some(ADMIN_TOKEN), None).await;
+    assert_eq!(status, 200);
+    let servers: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(servers[0]["name"], "lobby");
+    assert_eq!(servers[0]["ctrlChannelId"], CTRL_CHANNEL.to_string());
+    assert_eq!(servers[0]["tags"], json!({ "region": "eu" }));
+
+    let path = format!("/servers/{}", uuid);
+    let (status, body) = http(addr, "GET", &path, Some(ADMIN_TOKEN), None).await;
+    assert_eq!(status, 200);
+    let details: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(details["uuid"], uuid.to_string());
+    assert_eq!(details["authenticated"], true);
+    assert_eq!(details["capabilities"], json!(["commandResponse"]));
+
+    let (status, _) = http(addr, "GET", "/servers/nope", Some(ADMIN_TOKEN), None).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn commands_report_the_servers_they_were_sent_to() {
+    let bridge = Bridge::start().await;
+    let addr = bridge.serve_admin();
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+    let uuid = bridge.server_uuid("lobby").await.unwrap();
+
+    let command = json!({ "channelId": CTRL_CHANNEL.to_string(), "on": "lobby", "run": ["list"] });
+    let (status, body) = http(addr, "POST", "/commands", Some(ADMIN_TOKEN), Some(command)).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap(),
+        json!({ "sent": [{ "uuid": uuid, "name": "lobby" }], "failed": [] })
+    );
+    assert_eq!(plugin.recv_id(0).await["exec"]["run"], json!(["list"]));
+
+    let command = json!({ "channelId": CTRL_CHANNEL.to_string(), "on": "survival" });
+    let (status, _) = http(addr, "POST", "/commands", Some(ADMIN_TOKEN), Some(command)).await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn kicked_servers_are_disconnected() {
+    let bridge = Bridge::start().await;
+    let addr = bridge.serve_admin();
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+    let uuid = bridge.server_uuid("lobby").await.unwrap();
+
+    let path = format!("/servers/{}/kick", uuid);
+    let (status, body) = http(addr, "POST", &path, Some(ADMIN_TOKEN), None).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap(),
+        json!({ "kicked": uuid })
+    );
+
+    let mut packets = vec![];
+    while let Some(Ok(message)) = plugin.ws.next().await {
+        if let Message::Text(text) = message {
+            packets.push(serde_json::from_str::<Value>(&text).unwrap());
+        }
+    }
+    assert_eq!(packets.last().unwrap()["error"], "Kicked");
+    assert_eq!(bridge.server_uuid("lobby").await, None);
+}
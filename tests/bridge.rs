@@ -0,0 +1,714 @@
+//! Drives the bridge end to end against an in-memory chat platform, with plugins connected over
+//! real websockets
+mod common;
+
+use common::{Bridge, Plugin, Tls, ADMIN, CTRL_CHANNEL, STRANGER, TIMEOUT};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
+use tc_discord::{
+    audit::Outcome,
+    discord::{
+        chat_bridge, confirmation::ClickReply, notifications, platform::Post, slash_command,
+    },
+};
+use tokio_tungstenite::tungstenite::Message;
+use twilight_model::{
+    application::{
+        callback::InteractionResponse,
+        component::Component,
+        interaction::{application_command::CommandData, ApplicationCommand, InteractionType},
+    },
+    id::{ApplicationId, ChannelId, CommandId, InteractionId},
+};
+
+#[tokio::test]
+async fn command_response_is_posted_in_the_control_channel() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - say hi\n```")
+        .await;
+
+    let server_run = plugin.recv_id(0).await;
+    assert_eq!(server_run["exec"]["run"], json!(["say hi"]));
+    plugin
+        .send(json!({
+            "id": 2,
+            "requestId": server_run["requestId"],
+            "run": ["said hi"],
+        }))
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, "Command response");
+    match &bridge.recorder.posts()[0] {
+        Post::Embed { channel_id, embed } => {
+            assert_eq!(channel_id.get(), CTRL_CHANNEL);
+            assert!(embed
+                .fields
+                .iter()
+                .any(|field| field.value.contains("said hi")));
+        }
+        other => panic!("Expected an embed, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn servers_flooded_with_commands_receive_all_of_them() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    // More than fit the outgoing queue, while the client also answers packets of its own
+    let commands = async {
+        for _ in 0..40 {
+            bridge
+                .message(ADMIN, "```yaml\non: lobby\nrun:\n  - list\n```")
+                .await;
+        }
+    };
+    let invalid = async {
+        for _ in 0..40 {
+            plugin.send(json!({ "id": 99 })).await;
+        }
+    };
+    tokio::time::timeout(TIMEOUT, async { tokio::join!(commands, invalid) })
+        .await
+        .expect("The bridge got stuck sending commands");
+
+    let mut received = HashMap::new();
+    for _ in 0..80 {
+        let id = plugin.recv().await["id"].as_i64().unwrap();
+        *received.entry(id).or_insert(0) += 1;
+    }
+    assert_eq!(received[&0], 40);
+    assert_eq!(received[&-1], 40);
+}
+
+#[tokio::test]
+async fn regex_selectors_reach_every_matching_server() {
+    let bridge = Bridge::start().await;
+    let mut lobby = Plugin::register(&bridge, "lobby-1", None).await;
+    let mut lobby2 = Plugin::register(&bridge, "lobby-2", None).await;
+    let mut survival = Plugin::register(&bridge, "survival", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby-.*\nrun:\n  - list\n```")
+        .await;
+
+    lobby.recv_id(0).await;
+    lobby2.recv_id(0).await;
+    survival.assert_nothing_received().await;
+    assert!(bridge.recorder.posts().is_empty());
+}
+
+#[tokio::test]
+async fn invalid_yaml_is_answered_with_an_error() {
+    let bridge = Bridge::start().await;
+
+    bridge.message(ADMIN, "```yaml\non: [lobby\n```").await;
+
+    assert_eq!(bridge.title_of_post(1).await, ":x: Error parsing command");
+    assert!(matches!(bridge.recorder.posts()[0], Post::Reply { .. }));
+}
+
+#[tokio::test]
+async fn unknown_servers_are_answered_with_an_error() {
+    let bridge = Bridge::start().await;
+    let _plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: survival\nrun:\n  - list\n```")
+        .await;
+
+    assert_eq!(
+        bridge.title_of_post(1).await,
+        ":x: Could not find any servers"
+    );
+}
+
+#[tokio::test]
+async fn commands_need_permission() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(STRANGER, "```yaml\non: lobby\nrun:\n  - stop\n```")
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, ":x: Permission denied");
+    plugin.assert_nothing_received().await;
+}
+
+#[tokio::test]
+async fn unauthenticated_plugins_are_refused() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::connect_unauthenticated(bridge.addr().await).await;
+    plugin.recv_id(1).await;
+
+    plugin.send(json!({ "id": 3, "response": "00" })).await;
+
+    assert_eq!(plugin.recv_id(-1).await["error"], "Unauthorized");
+}
+
+#[tokio::test]
+async fn malformed_packets_are_answered_with_an_error() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::connect(bridge.addr().await).await;
+
+    plugin.send(json!({ "id": 0, "nom": "lobby" })).await;
+
+    assert_eq!(
+        plugin.recv_id(-1).await["error"],
+        "PacketDeserializationError"
+    );
+}
+
+#[tokio::test]
+async fn bridge_channels_must_differ_from_control_channels() {
+    let bridge = Bridge::start().await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+    plugin
+        .send(json!({ "id": 6, "bridgeChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    assert_eq!(plugin.recv_id(-1).await["error"], "InvalidBridgeChannel");
+
+    let mut plugin = Plugin::connect(bridge.addr().await).await;
+    plugin.send(json!({ "id": 0, "name": "survival" })).await;
+    plugin
+        .send(json!({ "id": 6, "bridgeChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    plugin
+        .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    assert_eq!(plugin.recv_id(-1).await["error"], "InvalidBridgeChannel");
+    assert_eq!(bridge.server_uuid("survival").await, None);
+}
+
+#[tokio::test]
+async fn chat_is_published_to_the_bridge_channel() {
+    let bridge = Bridge::start().await;
+    let mut chat = bridge.ws_mgr.lock().await.subscribe_chat();
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    plugin
+        .send(json!({ "id": 5, "player": "steve", "text": "hi" }))
+        .await;
+    assert_eq!(plugin.recv_id(-1).await["error"], "NoBridgeChannel");
+
+    plugin
+        .send(json!({ "id": 6, "bridgeChannelId": "200" }))
+        .await;
+    plugin
+        .send(json!({ "id": 5, "player": "steve", "text": "hi" }))
+        .await;
+    let event = tokio::time::timeout(TIMEOUT, chat.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (
+            event.bridge_channel_id.as_str(),
+            event.player.as_str(),
+            event.text.as_str()
+        ),
+        ("200", "steve", "hi")
+    );
+}
+
+#[tokio::test]
+async fn game_chat_is_posted_through_a_webhook() {
+    let bridge = Bridge::start().await;
+    let events = bridge.ws_mgr.lock().await.subscribe_chat();
+    let recorder = bridge.recorder.clone();
+    tokio::spawn(async move { chat_bridge::run(&*recorder, events).await });
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    plugin
+        .send(json!({ "id": 6, "bridgeChannelId": "200" }))
+        .await;
+    plugin
+        .send(json!({ "id": 5, "player": "steve", "text": "hi", "avatar": "https://example.com/steve.png" }))
+        .await;
+    match &bridge.recorder.wait_for(1).await[0] {
+        Post::Webhook {
+            channel_id,
+            message,
+        } => {
+            assert_eq!(channel_id.get(), 200);
+            assert_eq!(
+                (
+                    message.username.as_str(),
+                    message.content.as_str(),
+                    message.avatar_url.as_deref()
+                ),
+                ("steve", "hi", Some("https://example.com/steve.png"))
+            );
+        }
+        other => panic!("Expected a webhook message, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn slash_commands_are_answered_through_the_platform() {
+    let bridge = Bridge::start().await;
+    let _plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    let command = ApplicationCommand {
+        application_id: ApplicationId::new(1).unwrap(),
+        channel_id: ChannelId::new(CTRL_CHANNEL).unwrap(),
+        data: CommandData {
+            id: CommandId::new(1).unwrap(),
+            name: "list".to_string(),
+            options: vec![],
+            resolved: None,
+        },
+        guild_id: None,
+        id: InteractionId::new(3).unwrap(),
+        kind: InteractionType::ApplicationCommand,
+        member: None,
+        token: "token".to_string(),
+        user: None,
+    };
+    slash_command::handle_interaction(
+        &command,
+        &*bridge.recorder,
+        bridge.ws_mgr.clone(),
+        &bridge.config.permissions,
+        &bridge.audit,
+        &bridge.confirmations,
+    )
+    .await
+    .unwrap();
+
+    match &bridge.recorder.posts()[0] {
+        Post::Response {
+            interaction_id,
+            response: InteractionResponse::ChannelMessageWithSource(data),
+        } => {
+            assert_eq!(interaction_id.get(), 3);
+            assert_eq!(data.embeds[0].fields[0].name, "lobby");
+        }
+        other => panic!("Expected an interaction response, got {:?}", other),
+    }
+}
+
+/// Register a resumable plugin as `name`, returning it with its resume token
+async fn register_resumable(bridge: &Bridge, name: &str) -> (Plugin, String) {
+    let mut plugin = Plugin::connect_resumable(bridge.addr().await).await;
+    plugin.send(json!({ "id": 0, "name": name })).await;
+    plugin
+        .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    let token = plugin.recv_id(5).await["resumeToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    bridge.wait_for_server(name).await;
+    (plugin, token)
+}
+
+#[tokio::test]
+async fn resumed_sessions_replay_what_was_not_acknowledged() {
+    let bridge = Bridge::start_with("resume:\n  gracePeriod: 5").await;
+    let (mut plugin, token) = register_resumable(&bridge, "lobby").await;
+    let uuid = bridge.server_uuid("lobby").await;
+
+    for command in ["first", "second"] {
+        bridge
+            .message(
+                ADMIN,
+                &format!("```yaml\non: lobby\nrun:\n  - {}\n```", command),
+            )
+            .await;
+    }
+    let first = plugin.recv_id(0).await;
+    let second = plugin.recv_id(0).await;
+    assert!(second["seq"].as_u64() > first["seq"].as_u64());
+    plugin.send(json!({ "id": 9, "seq": first["seq"] })).await;
+    // Also makes sure the acknowledgement was handled, the error is replayed too
+    plugin.assert_nothing_received().await;
+    drop(plugin);
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - third\n```")
+        .await;
+    let mut plugin = Plugin::connect_resumable(bridge.addr().await).await;
+    plugin.send(json!({ "id": 10, "resumeToken": token })).await;
+
+    assert_eq!(plugin.recv().await, json!({ "id": 6, "replayed": 3 }));
+    let replayed = plugin.recv().await;
+    assert_eq!(replayed["exec"]["run"], json!(["second"]));
+    assert_eq!(replayed["seq"], second["seq"]);
+    assert_eq!(plugin.recv().await["error"], "PacketInvalidID");
+    let replayed = plugin.recv().await;
+    assert_eq!(replayed["exec"]["run"], json!(["third"]));
+    assert!(replayed["seq"].as_u64() > second["seq"].as_u64());
+    assert_eq!(bridge.server_uuid("lobby").await, uuid);
+}
+
+#[tokio::test]
+async fn sessions_can_not_be_resumed_after_the_grace_period() {
+    let bridge = Bridge::start_with("resume:\n  gracePeriod: 1").await;
+    let (plugin, token) = register_resumable(&bridge, "lobby").await;
+    drop(plugin);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(bridge.server_uuid("lobby").await, None);
+    let mut plugin = Plugin::connect_resumable(bridge.addr().await).await;
+    plugin.send(json!({ "id": 10, "resumeToken": token })).await;
+    assert_eq!(plugin.recv_id(-1).await["error"], "ResumeFailed");
+}
+
+#[tokio::test]
+async fn disconnecting_servers_are_announced() {
+    let bridge = Bridge::start().await;
+    let events = bridge.ws_mgr.lock().await.subscribe();
+    let recorder = bridge.recorder.clone();
+    tokio::spawn(async move { notifications::run(&*recorder, events, HashMap::new()).await });
+
+    let plugin = Plugin::register(&bridge, "lobby", None).await;
+    assert_eq!(bridge.title_of_post(1).await, "Server online");
+
+    drop(plugin);
+    assert_eq!(bridge.title_of_post(2).await, "Server offline");
+}
+
+#[tokio::test]
+async fn servers_missing_heartbeats_are_announced_offline() {
+    let bridge = Bridge::start_with("heartbeat:\n  interval: 1\n  missedBeats: 1").await;
+    let events = bridge.ws_mgr.lock().await.subscribe();
+    let recorder = bridge.recorder.clone();
+    tokio::spawn(async move { notifications::run(&*recorder, events, HashMap::new()).await });
+
+    // Connected, but never reading again, so pings go unanswered
+    let _plugin = Plugin::register(&bridge, "lobby", None).await;
+    assert_eq!(bridge.title_of_post(1).await, "Server online");
+
+    assert_eq!(bridge.title_of_post(2).await, "Server offline");
+    let reason = bridge.recorder.posts()[1].embed().unwrap().fields[0].clone();
+    assert_eq!(reason.name, "Reason");
+    assert_eq!(reason.value, "No heartbeat for 1 seconds");
+    assert_eq!(bridge.server_uuid("lobby").await, None);
+}
+
+#[tokio::test]
+async fn servers_are_known_by_their_client_certificate() {
+    let tls = Tls::generate();
+    let bridge = Bridge::start_with(&tls.config).await;
+    let mut events = bridge.ws_mgr.lock().await.subscribe();
+
+    let mut plugin = Plugin::connect_tls(bridge.addr().await, &tls).await;
+    plugin.send(json!({ "id": 0, "name": "lobby" })).await;
+    plugin
+        .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    bridge.wait_for_server("lobby").await;
+
+    let (info, client) = bridge
+        .ws_mgr
+        .lock()
+        .await
+        .get_connection_by_name("lobby", &CTRL_CHANNEL.to_string())
+        .unwrap();
+    assert_eq!(
+        info.certificate_fingerprint.as_ref(),
+        Some(&tls.fingerprint)
+    );
+    let details = client.lock().await.details();
+    assert_eq!(
+        details.info.certificate_fingerprint,
+        Some(tls.fingerprint.clone())
+    );
+    let online = tokio::time::timeout(TIMEOUT, events.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(online.certificate_fingerprint, Some(tls.fingerprint));
+}
+
+#[tokio::test]
+async fn conflicting_names_are_suffixed_when_joining_a_control_channel() {
+    let bridge = Bridge::start_with("nameConflict: suffix").await;
+    let mut first = Plugin::register(&bridge, "lobby", None).await;
+    let first_uuid = bridge.server_uuid("lobby").await;
+
+    let mut second = Plugin::connect(bridge.addr().await).await;
+    second.send(json!({ "id": 0, "name": "lobby" })).await;
+    second
+        .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    assert_eq!(second.recv_id(-1).await["error"], "NameConflict");
+    bridge.wait_for_server("lobby-2").await;
+    assert_eq!(bridge.server_uuid("lobby").await, first_uuid);
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - list\n```")
+        .await;
+    first.recv_id(0).await;
+    second.assert_nothing_received().await;
+}
+
+#[tokio::test]
+async fn plugins_from_before_going_away_only_see_the_socket_close() {
+    let bridge = Bridge::start().await;
+    // Says hello with protocol version 2
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    let drain = bridge.ws_mgr.lock().await.shutdown();
+    let drained = tokio::spawn(drain);
+
+    let mut packets = vec![];
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(Ok(message)) = plugin.ws.next().await {
+            match message {
+                Message::Text(text) => packets.push(serde_json::from_str::<Value>(&text).unwrap()),
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("The socket was never closed");
+    assert!(
+        packets.iter().all(|packet| packet["id"] != 7),
+        "{:?}",
+        packets
+    );
+    assert!(drained.await.unwrap());
+}
+
+#[tokio::test]
+async fn tag_selectors_reach_every_matching_server() {
+    let bridge = Bridge::start().await;
+    let mut eu = Plugin::register(&bridge, "lobby", Some(json!({ "region": "eu" }))).await;
+    let mut eu_test = Plugin::register(&bridge, "test-1", Some(json!({ "region": "eu" }))).await;
+    let mut us = Plugin::register(&bridge, "survival", Some(json!({ "region": "us" }))).await;
+
+    bridge
+        .message(
+            ADMIN,
+            "```yaml\non: tag:region=eu && !name:/test.*/\nrun:\n  - list\n```",
+        )
+        .await;
+
+    eu.recv_id(0).await;
+    for plugin in [&mut eu_test, &mut us] {
+        plugin.assert_nothing_received().await;
+    }
+}
+
+#[tokio::test]
+async fn invalid_selectors_are_answered_with_an_error() {
+    let bridge = Bridge::start().await;
+    let _plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: tag:region=eu &&\nrun:\n  - list\n```")
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, ":x: Invalid selector");
+}
+
+#[tokio::test]
+async fn dry_runs_list_servers_without_sending() {
+    let bridge = Bridge::start().await;
+    let mut lobby = Plugin::register(&bridge, "lobby", None).await;
+    let _survival = Plugin::register(&bridge, "survival", None).await;
+
+    bridge
+        .message(
+            ADMIN,
+            "```yaml\non: lobby\nrun:\n  - stop\ndry_run: true\n```",
+        )
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, "Dry run");
+    let posts = bridge.recorder.posts();
+    let field = &posts[0].embed().unwrap().fields[0];
+    assert_eq!(field.name, "Would be sent to 1 servers");
+    assert_eq!(field.value, "lobby");
+    lobby.assert_nothing_received().await;
+}
+
+#[tokio::test]
+async fn long_server_lists_are_cut_short() {
+    let bridge = Bridge::start().await;
+    let mut plugins = vec![];
+    for i in 0..30 {
+        let name = format!("lobby-{:02}-{}", i, "x".repeat(40));
+        plugins.push(Plugin::register(&bridge, &name, None).await);
+    }
+
+    bridge
+        .message(
+            ADMIN,
+            "```yaml\non: lobby-.*\nrun:\n  - stop\ndry_run: true\n```",
+        )
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, "Dry run");
+    let posts = bridge.recorder.posts();
+    let field = &posts[0].embed().unwrap().fields[0];
+    assert_eq!(field.name, "Would be sent to 30 servers");
+    assert!(field.value.chars().count() <= 1024, "{}", field.value);
+    assert!(field.value.ends_with(" more"), "{}", field.value);
+}
+
+/// The custom ids of the buttons on `post`
+fn buttons(post: &Post) -> Vec<String> {
+    match post {
+        Post::Reply { components, .. } => components
+            .iter()
+            .flat_map(|component| match component {
+                Component::ActionRow(row) => row.components.clone(),
+                other => vec![other.clone()],
+            })
+            .filter_map(|component| match component {
+                Component::Button(button) => button.custom_id,
+                _ => None,
+            })
+            .collect(),
+        other => panic!("Expected a reply, got {:?}", other),
+    }
+}
+
+/// Outcomes audited in the control channel, newest first
+async fn outcomes(bridge: &Bridge) -> Vec<Outcome> {
+    let records = bridge.audit.recent_commands(CTRL_CHANNEL, 0, 10).await;
+    records
+        .unwrap()
+        .into_iter()
+        .map(|(command, _)| command.outcome)
+        .collect()
+}
+
+fn title(reply: ClickReply) -> String {
+    match reply {
+        ClickReply::Update(embed) | ClickReply::Private(embed) => embed.title.unwrap(),
+    }
+}
+
+#[tokio::test]
+async fn denied_commands_wait_for_confirmation() {
+    let bridge = Bridge::start_with("confirmation:\n  denyPattern: stop|ban|op").await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - stop\n```")
+        .await;
+
+    assert_eq!(bridge.title_of_post(1).await, "Confirmation needed");
+    let ids = buttons(&bridge.recorder.posts()[0]);
+    assert_eq!(ids.len(), 2);
+    let confirm = ids.iter().find(|id| id.starts_with("confirm:")).unwrap();
+    plugin.assert_nothing_received().await;
+
+    let reply = bridge.click(STRANGER, confirm).await;
+    assert!(matches!(reply, ClickReply::Private(_)));
+    assert_eq!(title(reply), ":x: Permission denied");
+
+    let reply = bridge.click(ADMIN, confirm).await;
+    assert!(matches!(reply, ClickReply::Update(_)));
+    assert_eq!(title(reply), "Command confirmed");
+    let server_run = plugin.recv_id(0).await;
+    assert_eq!(server_run["exec"]["run"], json!(["stop"]));
+
+    // Held and sent under the id on the buttons
+    let held_id = confirm.trim_start_matches("confirm:");
+    assert_eq!(server_run["requestId"], held_id);
+    let records = bridge
+        .audit
+        .recent_commands(CTRL_CHANNEL, 0, 10)
+        .await
+        .unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(matches!(records[0].0.outcome, Outcome::Sent { .. }));
+    assert!(matches!(records[1].0.outcome, Outcome::Held { .. }));
+    for (command, _) in &records {
+        assert_eq!(command.request_id.to_string(), held_id);
+    }
+
+    assert_eq!(
+        title(bridge.click(ADMIN, confirm).await),
+        ":x: Confirmation expired"
+    );
+}
+
+#[tokio::test]
+async fn commands_reaching_too_many_servers_can_be_cancelled() {
+    let bridge = Bridge::start_with("confirmation:\n  maxTargets: 1").await;
+    let mut lobby = Plugin::register(&bridge, "lobby", None).await;
+    let mut survival = Plugin::register(&bridge, "survival", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - stop\n```")
+        .await;
+    lobby.recv_id(0).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: .*\nrun:\n  - list\n```")
+        .await;
+    assert_eq!(bridge.title_of_post(1).await, "Confirmation needed");
+    let ids = buttons(&bridge.recorder.posts()[0]);
+    let cancel = ids.iter().find(|id| id.starts_with("cancel:")).unwrap();
+
+    assert_eq!(
+        title(bridge.click(ADMIN, cancel).await),
+        "Command cancelled"
+    );
+    for plugin in [&mut lobby, &mut survival] {
+        plugin.assert_nothing_received().await;
+    }
+    let outcomes = outcomes(&bridge).await;
+    assert!(matches!(outcomes[0], Outcome::Cancelled { by: ADMIN }));
+    assert!(matches!(outcomes[1], Outcome::Held { .. }));
+}
+
+#[tokio::test]
+async fn confirming_after_the_targets_changed_sends_nothing() {
+    let bridge = Bridge::start_with("confirmation:\n  maxTargets: 1").await;
+    let mut lobby = Plugin::register(&bridge, "lobby", None).await;
+    let mut survival = Plugin::register(&bridge, "survival", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: .*\nrun:\n  - list\n```")
+        .await;
+    assert_eq!(bridge.title_of_post(1).await, "Confirmation needed");
+    let ids = buttons(&bridge.recorder.posts()[0]);
+    let confirm = ids.iter().find(|id| id.starts_with("confirm:")).unwrap();
+
+    let mut creative = Plugin::register(&bridge, "creative", None).await;
+    assert_eq!(
+        title(bridge.click(ADMIN, confirm).await),
+        ":x: Targets changed"
+    );
+    for plugin in [&mut lobby, &mut survival, &mut creative] {
+        plugin.assert_nothing_received().await;
+    }
+    assert!(matches!(
+        outcomes(&bridge).await[0],
+        Outcome::TargetsChanged
+    ));
+}
+
+#[tokio::test]
+async fn unconfirmed_commands_time_out() {
+    let bridge = Bridge::start_with("confirmation:\n  denyPattern: stop\n  timeout: 1").await;
+    let mut plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - /stop now\n```")
+        .await;
+    assert_eq!(bridge.title_of_post(1).await, "Confirmation needed");
+    let ids = buttons(&bridge.recorder.posts()[0]);
+    let confirm = ids.iter().find(|id| id.starts_with("confirm:")).unwrap();
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(matches!(outcomes(&bridge).await[0], Outcome::Expired));
+    assert_eq!(
+        title(bridge.click(ADMIN, confirm).await),
+        ":x: Confirmation expired"
+    );
+    plugin.assert_nothing_received().await;
+}
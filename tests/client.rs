@@ -0,0 +1,107 @@
+//! The client SDK against a real bridge
+mod common;
+
+use common::{Bridge, Tls, ADMIN, CTRL_CHANNEL, SECRET, TIMEOUT};
+use futures::StreamExt;
+use std::time::Duration;
+use tc_discord_client::{Client, ClientConfig};
+
+async fn connect(bridge: &Bridge, name: &str) -> (Client, tc_discord_client::Commands) {
+    let mut config = ClientConfig::new(format!("ws://{}", bridge.addr().await), SECRET);
+    config.reconnect_delay = Duration::from_millis(50);
+    let (client, commands) = Client::connect(config).await.unwrap();
+    client.set_name(name).unwrap();
+    client
+        .set_control_channel(CTRL_CHANNEL.to_string())
+        .unwrap();
+    bridge.wait_for_server(name).await;
+    (client, commands)
+}
+
+#[tokio::test]
+async fn commands_are_streamed_and_answered() {
+    let bridge = Bridge::start().await;
+    let (client, mut commands) = connect(&bridge, "lobby").await;
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - say hi\n```")
+        .await;
+
+    let command = tokio::time::timeout(TIMEOUT, commands.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(command.exec.run, vec!["say hi"]);
+    client
+        .respond(
+            command.request_id,
+            vec!["said hi".to_string()],
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+
+    assert_eq!(bridge.title_of_post(1).await, "Command response");
+}
+
+#[tokio::test]
+async fn kicked_clients_reconnect_and_register_again() {
+    let bridge = Bridge::start().await;
+    let (_client, mut commands) = connect(&bridge, "lobby").await;
+    let first = bridge.server_uuid("lobby").await.unwrap();
+
+    let (_, server) = bridge
+        .ws_mgr
+        .lock()
+        .await
+        .get_connection_by_uuid(first)
+        .unwrap();
+    server.lock().await.kick();
+
+    tokio::time::timeout(TIMEOUT, async {
+        while bridge
+            .server_uuid("lobby")
+            .await
+            .is_none_or(|uuid| uuid == first)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Client never registered again");
+
+    bridge
+        .message(ADMIN, "```yaml\non: lobby\nrun:\n  - list\n```")
+        .await;
+    let command = tokio::time::timeout(TIMEOUT, commands.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(command.exec.run, vec!["list"]);
+}
+
+#[tokio::test]
+async fn clients_connect_over_tls_with_a_certificate() {
+    let tls = Tls::generate();
+    let bridge = Bridge::start_with(&tls.config).await;
+
+    let url = format!("wss://localhost:{}", bridge.addr().await.port());
+    let mut config = ClientConfig::new(url, SECRET);
+    config.ca = Some(tls.ca.clone());
+    config.cert = Some(tls.cert.clone());
+    config.key = Some(tls.key.clone());
+    let (client, _commands) = Client::connect(config).await.unwrap();
+    client.set_name("lobby").unwrap();
+    client
+        .set_control_channel(CTRL_CHANNEL.to_string())
+        .unwrap();
+    bridge.wait_for_server("lobby").await;
+
+    let (info, _) = bridge
+        .ws_mgr
+        .lock()
+        .await
+        .get_connection_by_name("lobby", &CTRL_CHANNEL.to_string())
+        .unwrap();
+    assert_eq!(info.certificate_fingerprint, Some(tls.fingerprint));
+}
@@ -0,0 +1,440 @@
+//! A bridge running against an in-memory chat platform, and plugins speaking raw packets to it
+#![allow(dead_code)]
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{fs, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tc_discord::{
+    admin::{self, AdminConfig},
+    audit::AuditLog,
+    config::Config,
+    discord::{
+        confirmation::{Click, ClickReply, Confirmations},
+        handle_command_message,
+        permissions::Invoker,
+        platform::Recorder,
+        CommandMessage,
+    },
+    metrics::{self, Metrics, MetricsConfig},
+    ws::{Am, PluginStream, WsManager},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_rustls::{
+    rustls::{internal::pemfile, ClientConfig},
+    webpki::DNSNameRef,
+    TlsConnector,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use twilight_model::id::{ChannelId, MessageId, UserId};
+use uuid::Uuid;
+
+pub const SECRET: &str = "plugin secret";
+pub const CTRL_CHANNEL: u64 = 100;
+/// Allowed to do anything in `CTRL_CHANNEL`
+pub const ADMIN: u64 = 1;
+/// Allowed nothing
+pub const STRANGER: u64 = 2;
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+pub const ADMIN_TOKEN: &str = "admin token";
+
+pub struct Bridge {
+    pub ws_mgr: Am<WsManager>,
+    pub recorder: Arc<Recorder>,
+    pub config: Config,
+    pub audit: Arc<AuditLog>,
+    pub metrics: Arc<Metrics>,
+    pub confirmations: Confirmations,
+}
+
+impl Bridge {
+    pub async fn start() -> Self {
+        Self::start_with("").await
+    }
+
+    /// Start with `extra` appended to the config
+    pub async fn start_with(extra: &str) -> Self {
+        let audit_log = std::env::temp_dir().join(format!("tc-discord-{}.jsonl", Uuid::new_v4()));
+        let mut config: Config = serde_yaml::from_str(&format!(
+            r#"
+bind: 127.0.0.1:0
+auditLog: {audit_log}
+discord:
+  token:
+    value: unused
+plugins:
+  secret:
+    value: {secret}
+permissions:
+  "{channel}":
+    - users: [{admin}]
+      servers: ".*"
+      operations: [run, query, set]
+{extra}
+"#,
+            audit_log = audit_log.display(),
+            secret = SECRET,
+            channel = CTRL_CHANNEL,
+            admin = ADMIN,
+            extra = extra,
+        ))
+        .unwrap();
+
+        let audit = Arc::new(AuditLog::new(audit_log));
+        let recorder = Arc::new(Recorder::default());
+        let metrics = Arc::new(Metrics::default());
+        let mut ws_mgr = WsManager::new(&config, audit.clone(), metrics.clone()).await;
+        ws_mgr.set_platform(recorder.clone()).await;
+
+        let confirmations =
+            Confirmations::new(std::mem::take(&mut config.confirmation), audit.clone());
+        Self {
+            ws_mgr: Arc::new(Mutex::new(ws_mgr)),
+            recorder,
+            config,
+            audit,
+            metrics,
+            confirmations,
+        }
+    }
+
+    /// Serve the admin API, with `ADMIN_TOKEN` as its token
+    pub fn serve_admin(&self) -> SocketAddr {
+        let addr = free_addr();
+        let config: AdminConfig =
+            serde_yaml::from_str(&format!("bind: {}\ntoken:\n  value: {}", addr, ADMIN_TOKEN))
+                .unwrap();
+        tokio::spawn(admin::serve(
+            config,
+            self.ws_mgr.clone(),
+            self.audit.clone(),
+        ));
+        addr
+    }
+
+    /// Serve `/metrics`
+    pub fn serve_metrics(&self) -> SocketAddr {
+        let addr = free_addr();
+        tokio::spawn(metrics::serve(
+            MetricsConfig { bind: addr },
+            self.metrics.clone(),
+            self.ws_mgr.clone(),
+        ));
+        addr
+    }
+
+    pub async fn addr(&self) -> SocketAddr {
+        self.ws_mgr.lock().await.local_addr()
+    }
+
+    /// Post a message in `CTRL_CHANNEL` as `user_id`
+    pub async fn message(&self, user_id: u64, content: &str) {
+        let message = CommandMessage {
+            channel_id: ChannelId::new(CTRL_CHANNEL).unwrap(),
+            message_id: MessageId::new(1).unwrap(),
+            invoker: Invoker {
+                user_id: UserId::new(user_id).unwrap(),
+                roles: vec![],
+            },
+            content: content.to_string(),
+        };
+        handle_command_message(
+            &*self.recorder,
+            &self.ws_mgr,
+            &self.config.permissions,
+            &self.audit,
+            &self.confirmations,
+            &message,
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Click the button with `custom_id` in `CTRL_CHANNEL` as `user_id`
+    pub async fn click(&self, user_id: u64, custom_id: &str) -> ClickReply {
+        let click = Click {
+            custom_id: custom_id.to_string(),
+            channel_id: ChannelId::new(CTRL_CHANNEL).unwrap(),
+            invoker: Invoker {
+                user_id: UserId::new(user_id).unwrap(),
+                roles: vec![],
+            },
+        };
+        self.confirmations
+            .click(&self.ws_mgr, &self.config.permissions, &click)
+            .await
+            .unwrap()
+    }
+
+    /// Wait for the `count`th post and return its embed title
+    pub async fn title_of_post(&self, count: usize) -> String {
+        let posts = tokio::time::timeout(TIMEOUT, self.recorder.wait_for(count))
+            .await
+            .expect("Nothing was posted");
+        posts[count - 1]
+            .embed()
+            .and_then(|embed| embed.title.clone())
+            .unwrap()
+    }
+
+    /// Wait until the bridge knows `name` by name
+    pub async fn wait_for_server(&self, name: &str) {
+        tokio::time::timeout(TIMEOUT, async {
+            while self
+                .ws_mgr
+                .lock()
+                .await
+                .get_connection_by_name(name, &CTRL_CHANNEL.to_string())
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Server never registered");
+    }
+
+    /// Uuid of the connection registered as `name`
+    pub async fn server_uuid(&self, name: &str) -> Option<Uuid> {
+        self.ws_mgr
+            .lock()
+            .await
+            .get_connection_by_name(name, &CTRL_CHANNEL.to_string())
+            .map(|(info, _)| info.uuid)
+    }
+}
+
+/// A CA, with certificates it signed for the bridge and for a plugin
+pub struct Tls {
+    /// Config for the bridge to only accept plugins with a certificate from the CA
+    pub config: String,
+    /// The CA's certificate
+    pub ca: PathBuf,
+    /// The plugin's certificate
+    pub cert: PathBuf,
+    /// The plugin's private key
+    pub key: PathBuf,
+    /// Connects to the bridge with the plugin's certificate
+    pub connector: TlsConnector,
+    /// Hex encoded SHA-256 fingerprint of the plugin's certificate
+    pub fingerprint: String,
+}
+
+impl Tls {
+    pub fn generate() -> Self {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        let bridge = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let plugin = rcgen::generate_simple_self_signed(vec!["plugin".to_string()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("tc-discord-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let write = |name: &str, pem: &str| {
+            let path = dir.join(name);
+            fs::write(&path, pem).unwrap();
+            path
+        };
+        let ca_pem = ca.serialize_pem().unwrap();
+        let config = format!(
+            "tls:\n  cert: {}\n  key: {}\n  clientCa: {}",
+            write(
+                "bridge.pem",
+                &bridge.serialize_pem_with_signer(&ca).unwrap()
+            )
+            .display(),
+            write("bridge-key.pem", &bridge.serialize_private_key_pem()).display(),
+            write("ca.pem", &ca_pem).display(),
+        );
+
+        // Signing again would give a different certificate, so everything uses this one
+        let plugin_pem = plugin.serialize_pem_with_signer(&ca).unwrap();
+        let plugin_cert = pemfile::certs(&mut plugin_pem.as_bytes()).unwrap();
+        let plugin_key = plugin.serialize_private_key_pem();
+        let mut client_config = ClientConfig::new();
+        client_config
+            .root_store
+            .add_pem_file(&mut ca_pem.as_bytes())
+            .unwrap();
+        client_config
+            .set_single_client_cert(
+                plugin_cert.clone(),
+                pemfile::pkcs8_private_keys(&mut plugin_key.as_bytes())
+                    .unwrap()
+                    .remove(0),
+            )
+            .unwrap();
+
+        Self {
+            config,
+            ca: dir.join("ca.pem"),
+            cert: write("plugin.pem", &plugin_pem),
+            key: write("plugin-key.pem", &plugin_key),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            fingerprint: hex::encode(Sha256::digest(&plugin_cert[0].0)),
+        }
+    }
+}
+
+/// An address nothing is listening on, for servers that can't report the port they bound
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Make an HTTP request to a server that may still be starting, returning the status and body
+pub async fn http(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> (u16, String) {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, addr
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+
+    let mut stream = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("Server never started");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("No response")
+        .unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+pub struct Plugin {
+    pub ws: WebSocketStream<Box<dyn PluginStream>>,
+}
+
+impl Plugin {
+    /// Connect and authenticate, without registering
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let mut plugin = Self::connect_unauthenticated(addr).await;
+        plugin.authenticate(json!(["commandResponse"])).await;
+        plugin
+    }
+
+    /// Connect and authenticate announcing the resume capability, without registering
+    pub async fn connect_resumable(addr: SocketAddr) -> Self {
+        let mut plugin = Self::connect_unauthenticated(addr).await;
+        plugin
+            .authenticate(json!(["commandResponse", "resume"]))
+            .await;
+        plugin
+    }
+
+    /// Connect over TLS with `tls`'s plugin certificate and authenticate, without registering
+    pub async fn connect_tls(addr: SocketAddr, tls: &Tls) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let localhost = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = tls.connector.connect(localhost, stream).await.unwrap();
+        let url = format!("wss://localhost:{}", addr.port());
+        let mut plugin = Self::open(url, Box::new(stream)).await;
+        plugin.authenticate(json!(["commandResponse"])).await;
+        plugin
+    }
+
+    /// Connect, leaving the challenge unanswered
+    pub async fn connect_unauthenticated(addr: SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Self::open(format!("ws://{}", addr), Box::new(stream)).await
+    }
+
+    async fn open(url: String, stream: Box<dyn PluginStream>) -> Self {
+        let (ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        Self { ws }
+    }
+
+    async fn authenticate(&mut self, capabilities: Value) {
+        let challenge = self.recv_id(1).await;
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(challenge["challenge"].as_str().unwrap().as_bytes());
+        let response = hex::encode(mac.finalize().into_bytes());
+
+        self.send(json!({ "id": 4, "version": 2, "capabilities": capabilities }))
+            .await;
+        self.send(json!({ "id": 3, "response": response })).await;
+        self.recv_id(2).await;
+    }
+
+    /// Connect, authenticate and register as `name` in `CTRL_CHANNEL`, tagged with `tags` before
+    /// going online if given
+    pub async fn register(bridge: &Bridge, name: &str, tags: Option<Value>) -> Self {
+        let mut plugin = Self::connect(bridge.addr().await).await;
+        if let Some(tags) = tags {
+            plugin.send(json!({ "id": 11, "tags": tags })).await;
+        }
+        plugin.send(json!({ "id": 0, "name": name })).await;
+        plugin
+            .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+            .await;
+        bridge.wait_for_server(name).await;
+        plugin
+    }
+
+    pub async fn send(&mut self, packet: Value) {
+        self.ws
+            .send(Message::Text(packet.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// The next packet
+    pub async fn recv(&mut self) -> Value {
+        tokio::time::timeout(TIMEOUT, async {
+            loop {
+                match self.ws.next().await {
+                    Some(Ok(Message::Text(text))) => return serde_json::from_str(&text).unwrap(),
+                    Some(Ok(_)) => {}
+                    other => panic!("Connection ended waiting for a packet, {:?}", other),
+                }
+            }
+        })
+        .await
+        .expect("Never received a packet")
+    }
+
+    /// Check that nothing is waiting to be received. Packets are answered in order, so if the
+    /// answer to an invalid packet comes first nothing was sent before it
+    pub async fn assert_nothing_received(&mut self) {
+        self.send(json!({ "id": 99 })).await;
+        assert_eq!(self.recv().await["error"], "PacketInvalidID");
+    }
+
+    /// The next packet with the given id, skipping any others
+    pub async fn recv_id(&mut self, id: i64) -> Value {
+        loop {
+            let packet = self.recv().await;
+            if packet["id"] == id {
+                return packet;
+            }
+        }
+    }
+}
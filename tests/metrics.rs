@@ -0,0 +1,59 @@
+//! The metrics endpoint, against a bridge with plugins connected
+mod common;
+
+use common::{http, Bridge, Plugin, CTRL_CHANNEL, TIMEOUT};
+use serde_json::json;
+use std::{net::SocketAddr, time::Duration};
+
+/// The `tc_discord_connected_servers` lines of a scrape
+async fn connected_servers(addr: SocketAddr) -> Vec<String> {
+    let (status, body) = http(addr, "GET", "/metrics", None, None).await;
+    assert_eq!(status, 200);
+    body.lines()
+        .filter(|line| line.starts_with("tc_discord_connected_servers{"))
+        .map(str::to_string)
+        .collect()
+}
+
+#[tokio::test]
+async fn only_registered_servers_are_counted() {
+    let bridge = Bridge::start().await;
+    let addr = bridge.serve_metrics();
+
+    let _unregistered = Plugin::connect(bridge.addr().await).await;
+    let _plugin = Plugin::register(&bridge, "lobby", None).await;
+
+    assert_eq!(
+        connected_servers(addr).await,
+        [format!(
+            "tc_discord_connected_servers{{ctrl_channel_id=\"{}\"}} 1",
+            CTRL_CHANNEL
+        )]
+    );
+}
+
+#[tokio::test]
+async fn suspended_sessions_are_not_counted() {
+    let bridge = Bridge::start_with("resume:\n  gracePeriod: 60").await;
+    let addr = bridge.serve_metrics();
+
+    let mut plugin = Plugin::connect_resumable(bridge.addr().await).await;
+    plugin.send(json!({ "id": 0, "name": "lobby" })).await;
+    plugin
+        .send(json!({ "id": 1, "ctrlChannelId": CTRL_CHANNEL.to_string() }))
+        .await;
+    plugin.recv_id(5).await;
+    bridge.wait_for_server("lobby").await;
+    assert_eq!(connected_servers(addr).await.len(), 1);
+
+    drop(plugin);
+    tokio::time::timeout(TIMEOUT, async {
+        while !connected_servers(addr).await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("The suspended session was still counted");
+    // Still waiting to be resumed
+    assert!(bridge.server_uuid("lobby").await.is_some());
+}
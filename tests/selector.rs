@@ -0,0 +1,156 @@
+//! The `on` selector language, evaluated against server metadata
+use std::collections::BTreeMap;
+use tc_discord::ws::{Selector, ServerInfo};
+
+fn server(name: &str, tags: &[(&str, &str)]) -> ServerInfo {
+    ServerInfo {
+        name: name.to_string(),
+        tags: tags
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<BTreeMap<String, String>>(),
+        ..Default::default()
+    }
+}
+
+/// Names of the servers `selector` matches
+fn select(selector: &str, servers: &[ServerInfo]) -> Vec<String> {
+    let selector = Selector::parse(selector).unwrap();
+    servers
+        .iter()
+        .filter(|server| selector.matches(server))
+        .map(|server| server.name.clone())
+        .collect()
+}
+
+fn servers() -> Vec<ServerInfo> {
+    vec![
+        server("lobby-1", &[("region", "eu"), ("mode", "lobby")]),
+        server("lobby-2", &[("region", "us"), ("mode", "lobby")]),
+        server("survival", &[("region", "eu"), ("mode", "survival")]),
+        server("test-lobby", &[("region", "eu"), ("env", "staging")]),
+    ]
+}
+
+#[test]
+fn selectors_without_terms_match_whole_names() {
+    assert_eq!(select("lobby-.*", &servers()), ["lobby-1", "lobby-2"]);
+    assert_eq!(select("survival", &servers()), ["survival"]);
+    assert_eq!(select("lobby", &servers()), Vec::<String>::new());
+}
+
+#[test]
+fn prefixes_inside_names_are_not_terms() {
+    let servers = [server("backup-tag:old", &[]), server("lobby-1", &[])];
+    assert_eq!(select("backup-tag:old", &servers), ["backup-tag:old"]);
+    assert_eq!(select("backup-.*", &servers), ["backup-tag:old"]);
+    assert_eq!(
+        select("!tag:region && name:backup-*", &servers),
+        ["backup-tag:old"]
+    );
+}
+
+#[test]
+fn prefixes_say_how_to_match() {
+    assert_eq!(select("exact:lobby-1", &servers()), ["lobby-1"]);
+    assert_eq!(select("exact:lobby-.*", &servers()), Vec::<String>::new());
+    assert_eq!(
+        select("glob:*lobby*", &servers()),
+        ["lobby-1", "lobby-2", "test-lobby"]
+    );
+    assert_eq!(
+        select("regex:lobby-[12]", &servers()),
+        ["lobby-1", "lobby-2"]
+    );
+    assert_eq!(Selector::parse("regex:lobby(").unwrap_err().column, 7);
+}
+
+#[test]
+fn prefixes_combine_like_other_terms() {
+    assert_eq!(
+        select("exact:lobby-1 && tag:region=eu", &servers()),
+        ["lobby-1"]
+    );
+    assert_eq!(
+        select("glob:lobby-* && !tag:region=us", &servers()),
+        ["lobby-1"]
+    );
+    assert_eq!(
+        select("(regex:lobby-(1|2)) || exact:survival", &servers()),
+        ["lobby-1", "lobby-2", "survival"]
+    );
+    assert_eq!(
+        Selector::parse("exact:lobby tag:region=eu")
+            .unwrap_err()
+            .column,
+        13
+    );
+}
+
+#[test]
+fn names_match_globs_regexes_and_lists() {
+    assert_eq!(select("name:lobby-*", &servers()), ["lobby-1", "lobby-2"]);
+    assert_eq!(select("name:lobby-?", &servers()), ["lobby-1", "lobby-2"]);
+    assert_eq!(
+        select("name:/lobby-[12]/", &servers()),
+        ["lobby-1", "lobby-2"]
+    );
+    assert_eq!(select("name:/lobby/", &servers()), Vec::<String>::new());
+    assert_eq!(
+        select("name:survival,lobby-1", &servers()),
+        ["lobby-1", "survival"]
+    );
+}
+
+#[test]
+fn tags_match_values_or_presence() {
+    assert_eq!(
+        select("tag:region=eu", &servers()),
+        ["lobby-1", "survival", "test-lobby"]
+    );
+    assert_eq!(
+        select("tag:mode=lobby,survival", &servers()),
+        ["lobby-1", "lobby-2", "survival"]
+    );
+    assert_eq!(select("tag:env", &servers()), ["test-lobby"]);
+    assert_eq!(select("tag:env=*", &servers()), ["test-lobby"]);
+}
+
+#[test]
+fn terms_combine_with_precedence() {
+    assert_eq!(
+        select("tag:region=eu && !name:/test.*/", &servers()),
+        ["lobby-1", "survival"]
+    );
+    assert_eq!(
+        select(
+            "name:survival || tag:region=us && tag:mode=lobby",
+            &servers()
+        ),
+        ["lobby-2", "survival"]
+    );
+    assert_eq!(
+        select(
+            "(name:survival || tag:region=us) && tag:mode=lobby",
+            &servers()
+        ),
+        ["lobby-2"]
+    );
+    assert_eq!(select("!!tag:env", &servers()), ["test-lobby"]);
+}
+
+#[test]
+fn parse_errors_point_at_the_problem() {
+    for (selector, column) in [
+        ("tag:region=eu &&", 17),
+        ("tag:region=eu && survival", 18),
+        ("name:/lobby(/", 6),
+        ("name:/lobby", 6),
+        ("(name:lobby", 12),
+        ("tag:=eu", 5),
+        ("name:lobby tag:region=eu", 12),
+    ] {
+        let err = Selector::parse(selector).unwrap_err();
+        assert_eq!(err.column, column, "{}: {}", selector, err);
+    }
+}